@@ -0,0 +1,144 @@
+use crate::share::ShareRoot;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+// A shared file as seen by peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedFile {
+    pub name: String,
+    pub size: u64,
+    // Seconds since the unix epoch
+    pub modified: u64,
+    // Hex encoded SHA-256 of the contents
+    pub hash: String,
+}
+
+// Lists the share, only re-hashing files whose size or modified time changed
+#[derive(Default)]
+pub struct Catalogue {
+    hashes: HashMap<PathBuf, (u64, u64, String)>,
+    // Name of each shared file by hash, as of the last scan
+    names: HashMap<String, String>,
+}
+
+impl Catalogue {
+    // Files that cannot be read are left out, so one of them does not hide all the others
+    pub async fn scan(&mut self, share: &ShareRoot) -> Vec<SharedFile> {
+        let mut files = Vec::new();
+        for (name, path) in share.files() {
+            let (size, modified, hash) = match self.hash(&path).await {
+                Ok(hashed) => hashed,
+                Err(e) => {
+                    eprintln!("Skipping shared file {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            files.push(SharedFile {
+                name,
+                size,
+                modified,
+                hash,
+            });
+        }
+        self.names = files
+            .iter()
+            .map(|file| (file.hash.clone(), file.name.clone()))
+            .collect();
+        files
+    }
+
+    // Finds the name of a shared file by its hash. Peers can ask for any hash, so this never
+    // rescans, the watcher keeps the names up to date instead.
+    pub fn find(&self, hash: &str) -> Option<String> {
+        self.names.get(hash).cloned()
+    }
+
+    // Returns the size, modified time and hash of a file, hashing it only if it changed
+    pub async fn hash(&mut self, path: &Path) -> Result<(u64, u64, String), std::io::Error> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Some((s, m, hash)) = self.hashes.get(path) {
+            if *s == size && *m == modified {
+                return Ok((size, modified, hash.clone()));
+            }
+        }
+
+        let hash = hash_file(path).await?;
+        self.hashes
+            .insert(path.to_path_buf(), (size, modified, hash.clone()));
+        Ok((size, modified, hash))
+    }
+}
+
+// Hashes a file without loading it into memory
+pub async fn hash_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Formats a byte count for display, e.g. 2.1 MB
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Formats unix seconds as YYYY-MM-DD HH:MM in UTC
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60
+    )
+}
@@ -1,27 +1,49 @@
+use crate::catalogue::{self, Catalogue, SharedFile};
 use crate::commands;
+use crate::config::{OutputMode, Settings};
 use crate::directmessage::{DirectMessageBehaviour, DirectMessageBehaviourEvent};
+use crate::fetch::Fetches;
+use crate::filelist::{self, FileListBehaviour, FileListBehaviourEvent};
+use crate::identity;
+use crate::limits::{Held, Limits, Throttle};
+use crate::message::ChatMessage;
 use crate::network::{ChatBehaviour, ChatBehaviourEvent};
+use crate::nicknames::NicknameCache;
+use crate::offer::{OfferBehaviour, OfferBehaviourEvent, OfferMessage, Offers};
+use crate::policy::{Decision, ServePolicy};
+use crate::rooms::{Rooms, DEFAULT_ROOM};
+use crate::search::{self, Search};
 use crate::sendfile;
-use crate::sendfile::SendFileBehaviourEvent;
+use crate::sendfile::{
+    Direction, Downloads, FileChunk, FileError, FileResponse, SendFileBehaviourEvent, Transfers,
+    Uploads,
+};
+use crate::share::ShareRoot;
+use crate::store::BlobStore;
+use crate::watcher::{ShareUpdate, ShareWatcher};
 
 use futures::stream::StreamExt;
+use libp2p::identity::Keypair;
 use libp2p::kad::store::MemoryStore;
 use libp2p::kad::Mode;
 use libp2p::kad::QueryId;
-use libp2p::request_response;
-use libp2p::StreamProtocol;
+use libp2p::multiaddr::Protocol;
+use libp2p::request_response::{self, OutboundRequestId};
+use libp2p::Multiaddr;
 use libp2p::{gossipsub, kad, mdns, noise, swarm::SwarmEvent, tcp, yamux, PeerId};
 use std::collections::HashMap;
 use std::error::Error;
-use std::path::Path;
-use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tokio::{io, io::AsyncBufReadExt, select};
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::{io, io::AsyncBufReadExt, select, time};
 
 // Function to initialize the swarm with required configs
-fn initialize_swarm() -> Result<libp2p::Swarm<ChatBehaviour>, Box<dyn Error>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+fn initialize_swarm(
+    keypair: Keypair,
+    settings: &Settings,
+) -> Result<libp2p::Swarm<ChatBehaviour>, Box<dyn Error>> {
+    let protocols = &settings.protocols;
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -37,7 +59,7 @@ fn initialize_swarm() -> Result<libp2p::Swarm<ChatBehaviour>, Box<dyn Error>> {
                 )?,
                 gossipsub: gossipsub::Behaviour::new(
                     gossipsub::MessageAuthenticity::Signed(key.clone()),
-                    gossipsub::Config::default(),
+                    settings.gossipsub.clone(),
                 )?,
                 kademlia: kad::Behaviour::new(
                     key.public().to_peer_id(),
@@ -46,16 +68,35 @@ fn initialize_swarm() -> Result<libp2p::Swarm<ChatBehaviour>, Box<dyn Error>> {
                 request_response: sendfile::SendFileBehaviour {
                     request_response: libp2p::request_response::cbor::Behaviour::new(
                         [(
-                            StreamProtocol::new("/file-exchange/1"),
+                            protocols.file_exchange.clone(),
                             request_response::ProtocolSupport::Full,
                         )],
-                        request_response::Config::default(),
+                        request_response::Config::default()
+                            .with_request_timeout(sendfile::REQUEST_TIMEOUT),
                     ),
                 },
                 direct_message: DirectMessageBehaviour {
                     request_response: libp2p::request_response::cbor::Behaviour::new(
                         [(
-                            StreamProtocol::new("/direct-message/1"),
+                            protocols.direct_message.clone(),
+                            request_response::ProtocolSupport::Full,
+                        )],
+                        request_response::Config::default(),
+                    ),
+                },
+                file_list: FileListBehaviour {
+                    request_response: libp2p::request_response::cbor::Behaviour::new(
+                        [(
+                            protocols.file_list.clone(),
+                            request_response::ProtocolSupport::Full,
+                        )],
+                        request_response::Config::default(),
+                    ),
+                },
+                offer: OfferBehaviour {
+                    request_response: libp2p::request_response::cbor::Behaviour::new(
+                        [(
+                            protocols.file_offer.clone(),
                             request_response::ProtocolSupport::Full,
                         )],
                         request_response::Config::default(),
@@ -63,19 +104,24 @@ fn initialize_swarm() -> Result<libp2p::Swarm<ChatBehaviour>, Box<dyn Error>> {
                 },
             })
         })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(settings.idle_timeout))
         .build();
 
-    // Subscribe to the chat topic for gossipsub
+    // Subscribe to the default room for gossipsub
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&Rooms::topic(DEFAULT_ROOM))?;
     swarm
         .behaviour_mut()
         .gossipsub
-        .subscribe(&gossipsub::IdentTopic::new("chat"))?;
+        .subscribe(&ShareUpdate::topic())?;
 
     // Set Kademlia mode to server and set up listening on addresses
     swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    for addr in &settings.listen {
+        swarm.listen_on(addr.clone())?;
+    }
 
     Ok(swarm)
 }
@@ -85,12 +131,20 @@ fn handle_publish_message(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     topic: &gossipsub::IdentTopic,
     line: &str,
+    nickname: &str,
+    self_peer_id: &PeerId,
 ) {
-    if let Err(e) = swarm
-        .behaviour_mut()
-        .gossipsub
-        .publish(topic.clone(), line.as_bytes())
-    {
+    // Wrap the line in an envelope so receivers get the sender and time with it
+    let message = ChatMessage::new(self_peer_id, nickname, &topic.to_string(), line);
+    let data = match message.encode() {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Failed to encode message: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
         println!("Gossipsub publish error: {:?}", e);
     }
 }
@@ -101,10 +155,19 @@ fn handle_mdns_discovered(
     discovered_peers: Vec<(PeerId, libp2p::Multiaddr)>,
     nickname: &str,
     self_peer_id: &PeerId,
+    downloads: &mut Downloads,
+    transfers: &mut Transfers,
 ) {
     for (peer_id, _multiaddr) in discovered_peers {
         println!("New peer discovered: {peer_id}");
 
+        // Pick up any downloads that were interrupted when this peer went away
+        downloads.resume_peer(
+            &mut swarm.behaviour_mut().request_response,
+            transfers,
+            peer_id,
+        );
+
         // Add discovered peers to gossipsub and kademlia
         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
         swarm
@@ -112,30 +175,74 @@ fn handle_mdns_discovered(
             .kademlia
             .add_address(&peer_id, _multiaddr);
 
-        // Store the peer's nickname in kademlia
-        let name_store = kad::Record {
-            key: kad::RecordKey::new(&self_peer_id.to_string()),
-            value: nickname.as_bytes().to_vec(),
-            publisher: None,
-            expires: None,
-        };
+        put_nickname(swarm, nickname, self_peer_id);
 
-        if let Err(e) = swarm
+        // Look up the peer's nickname so it can be addressed by name
+        swarm
             .behaviour_mut()
             .kademlia
-            .put_record(name_store, kad::Quorum::One)
-        {
-            eprintln!("Failed to put record: {:?}", e);
+            .get_record(kad::RecordKey::new(&peer_id.to_string()));
+    }
+}
+
+// Store our nickname in kademlia so peers can address us by name
+fn put_nickname(swarm: &mut libp2p::Swarm<ChatBehaviour>, nickname: &str, self_peer_id: &PeerId) {
+    let name_store = kad::Record {
+        key: kad::RecordKey::new(&self_peer_id.to_string()),
+        value: nickname.as_bytes().to_vec(),
+        publisher: None,
+        expires: None,
+    };
+
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(name_store, kad::Quorum::One)
+    {
+        eprintln!("Failed to put record: {:?}", e);
+    }
+}
+
+// Dial the peers given with --bootstrap, for networks mDNS cannot reach
+fn handle_bootstrap(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    peers: &[Multiaddr],
+    nickname: &str,
+    self_peer_id: &PeerId,
+) {
+    for addr in peers {
+        // Bootstrap addresses are checked to end in /p2p/<peer id> when parsed
+        let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
+            continue;
+        };
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, addr.clone());
+        if let Err(e) = swarm.dial(addr.clone()) {
+            eprintln!("Failed to dial bootstrap peer {}: {:?}", addr, e);
         }
     }
+    if peers.is_empty() {
+        return;
+    }
+
+    // Fill the routing table from the bootstrap peers, then tell the network who we are
+    if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+        eprintln!("Failed to bootstrap: {:?}", e);
+    }
+    put_nickname(swarm, nickname, self_peer_id);
 }
 
 // Handle peer expiration from mDNS
 fn handle_mdns_expired(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     expired_peers: Vec<(PeerId, libp2p::Multiaddr)>,
+    rooms: &mut Rooms,
 ) {
     for (peer_id, _multiaddr) in expired_peers {
+        rooms.peer_expired(&peer_id);
         // Remove expired peers from gossipsub and kademlia
         println!("mDNS peer has expired: {peer_id}");
         swarm
@@ -152,103 +259,345 @@ fn handle_mdns_expired(
 // Handle the response of incoming messages from gossipsub
 fn handle_gossipsub_message(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
-    peer_id: PeerId,
-    msg_data: Vec<u8>,
-    pending_queries: &mut HashMap<QueryId, (PeerId, String)>,
+    message: gossipsub::Message,
+    pending_queries: &mut HashMap<QueryId, (PeerId, String, String)>,
+    nicknames: &NicknameCache,
 ) {
-    if let Ok(msg) = String::from_utf8(msg_data) {
+    let room = message.topic.to_string();
+
+    // Peers announce what they share on an internal topic rather than a room
+    if message.topic == ShareUpdate::topic().hash() {
+        // Messages are signed, so the source says who shared the files, not the nickname they claim
+        let (Some(source), Ok(update)) = (message.source, ShareUpdate::decode(&message.data))
+        else {
+            return;
+        };
+        let sender = match nicknames.get(&source) {
+            Some(nickname) if nickname == update.sender => nickname.to_string(),
+            Some(_) => return,
+            None => source.to_string(),
+        };
+        for file in &update.added {
+            println!(
+                "{} shared {} ({})",
+                sender,
+                file.name,
+                catalogue::format_size(file.size)
+            );
+        }
+        for file in &update.removed {
+            println!("{} stopped sharing {}", sender, file.name);
+        }
+        return;
+    }
+
+    // The peer that passed the message on may not be the one that wrote it
+    let Some(source) = message.source else {
+        return;
+    };
+
+    // Envelopes carry the nickname, which only stands if it is the one the signer published
+    if let Ok(chat) = ChatMessage::decode(&message.data) {
+        let sender = match nicknames.get(&source) {
+            Some(nickname) if nickname == chat.sender => nickname.to_string(),
+            _ => source.to_string(),
+        };
+        println!("[{}] #{} {}: {}", chat.time(), room, sender, chat.body);
+        return;
+    }
+
+    // Older peers publish the raw line, fall back to looking up their nickname
+    if let Ok(msg) = String::from_utf8(message.data) {
         let query_id = swarm
             .behaviour_mut()
             .kademlia
-            .get_record(kad::RecordKey::new(&peer_id.to_string()));
-        pending_queries.insert(query_id, (peer_id, msg));
+            .get_record(kad::RecordKey::new(&source.to_string()));
+        pending_queries.insert(query_id, (source, room, msg));
+    }
+}
+
+// Files hidden from some peers are never announced to the whole room or put in the DHT.
+// Rules are keyed by the listed name, so a symlink cannot announce a hidden file.
+fn public_files(state: &ChatState, files: &[SharedFile]) -> Vec<SharedFile> {
+    files
+        .iter()
+        .filter(|file| {
+            state
+                .share
+                .canonical_name(&file.name)
+                .is_some_and(|name| state.policy.is_public(&name))
+        })
+        .cloned()
+        .collect()
+}
+
+// Rescan the ByteBeam once changes have settled, announcing what was added and removed
+async fn handle_share_changed(swarm: &mut libp2p::Swarm<ChatBehaviour>, state: &mut ChatState) {
+    let files = state.catalogue.scan(&state.share).await;
+    state.search.announce(
+        &mut swarm.behaviour_mut().kademlia,
+        &public_files(state, &files),
+    );
+    let (added, removed) = state.watcher.update(files);
+
+    let update = ShareUpdate {
+        sender: state.nickname.clone(),
+        added: public_files(state, &added),
+        // Removed files can no longer be resolved, so only their own rule applies
+        removed: removed
+            .into_iter()
+            .filter(|file| state.policy.is_public(&file.name))
+            .collect(),
+    };
+    if update.is_empty() {
+        return;
+    }
+    let data = match update.encode() {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Failed to encode share update: {:?}", e);
+            return;
+        }
+    };
+    match swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(ShareUpdate::topic(), data)
+    {
+        // Nobody to tell yet, /ls shows the files once peers arrive
+        Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => {}
+        Err(e) => println!("Gossipsub publish error: {:?}", e),
     }
 }
 
 // Process outbound queries and manage record lookups
 fn process_outbound_query(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
     query_id: QueryId,
     result: kad::QueryResult,
-    pending_queries: &mut HashMap<QueryId, (PeerId, String)>,
-) {
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    let search = &mut state.search;
     match result {
         kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
-            record: kad::Record { value, .. },
+            record: kad::Record { key, value, .. },
             ..
         }))) => {
-            if let Some((peer_id, msg)) = pending_queries.remove(&query_id) {
+            // Name records are keyed by peer id, keep the nickname cache up to date
+            if let (Ok(Ok(peer_id)), Ok(nickname)) = (
+                std::str::from_utf8(key.as_ref()).map(PeerId::from_str),
+                std::str::from_utf8(&value),
+            ) {
+                state.nicknames.insert(peer_id, nickname.to_string());
+            }
+
+            if let Some((peer_id, room, msg)) = state.pending_queries.remove(&query_id) {
                 // Store query for future reference
                 if let Ok(nickname) = std::str::from_utf8(&value) {
-                    println!("{nickname}: {msg}");
+                    println!("#{room} {nickname}: {msg}");
                 } else {
-                    println!("{peer_id}: {msg}");
+                    println!("#{room} {peer_id}: {msg}");
                 }
             }
         }
         kad::QueryResult::GetRecord(Err(err)) => {
             println!("Failed to GET record. Err: {err:?}");
         }
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+            providers,
+            ..
+        })) => {
+            let self_peer_id = *swarm.local_peer_id();
+            let providers = providers
+                .into_iter()
+                .filter(|peer_id| *peer_id != self_peer_id)
+                .collect::<Vec<PeerId>>();
+
+            // Providers of a hash being fetched join the download
+            if state.fetches.found_providers(
+                &mut swarm.behaviour_mut().request_response,
+                &query_id,
+                &providers,
+            )? {
+                return Ok(());
+            }
+            if let Some(term) = search.found_providers(&query_id, providers.len()) {
+                for peer_id in providers {
+                    // Hashes identify the file exactly, keywords need the provider's list to name it
+                    if search::is_hash(&term) {
+                        println!("Peer {} has {}", peer_id, term);
+                    } else if let Ok(request_id) =
+                        swarm.behaviour_mut().file_list.send_req(peer_id, None)
+                    {
+                        search.add_listing(request_id, term.clone());
+                    }
+                }
+            }
+        }
+        kad::QueryResult::GetProviders(Ok(
+            kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. },
+        ))
+        | kad::QueryResult::GetProviders(Err(_)) => {
+            if state.fetches.finish_query(&mut state.transfers, &query_id) {
+                return Ok(());
+            }
+            if let Some((term, 0)) = search.finish_query(&query_id) {
+                println!("No peers found sharing '{}'", term);
+            }
+        }
+        kad::QueryResult::StartProviding(_) => {}
         kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key: _ })) => {}
         kad::QueryResult::PutRecord(Err(err)) => {
             println!("Failed to PUT record. Err: {err:?}");
         }
         _ => {}
     }
+    Ok(())
 }
 
 // Handle file requests and responses for file transfers
 async fn handle_send_file_event(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     send_file_event: SendFileBehaviourEvent,
+    state: &mut ChatState,
 ) -> Result<(), Box<dyn Error>> {
     match send_file_event {
         SendFileBehaviourEvent::RequestResponse(request_response::Event::Message {
+            peer,
             message,
-            ..
         }) => match message {
             request_response::Message::Request {
-                request, channel, ..
+                mut request,
+                channel,
+                ..
             } => {
-                sendfile::SendFileBehaviour::process_req(
-                    &mut swarm.behaviour_mut().request_response,
-                    request,
-                    channel,
-                )
-                .await?;
-            }
-            request_response::Message::Response { response, .. } => {
-                let filename = format!(
-                    "files/bytestream/{}",
-                    response.filename.replace(&['/', '\\'][..], "_") // prevent path traversal
-                );
+                let behaviour = &mut swarm.behaviour_mut().request_response;
 
-                // Create bytebeam directory if it has been deleted
-                if let Some(parent) = Path::new(&filename).parent() {
-                    tokio::fs::create_dir_all(parent).await?;
+                // Requests by content hash are served from whichever shared file has that hash,
+                // or else from the files we downloaded
+                let mut from_store = false;
+                if let Some(hash) = &request.hash {
+                    if let Some(name) = state.catalogue.find(hash) {
+                        request.filename = name;
+                    } else if let Some(name) = state.store.find(&mut state.catalogue, hash).await {
+                        request.filename = name;
+                        from_store = true;
+                    } else {
+                        behaviour.refuse(peer, &request, channel, FileError::NotFound);
+                        return Ok(());
+                    }
                 }
-
-                // Write the file and save to disc
-                match File::create(&filename).await {
-                    Ok(mut file) => {
-                        if let Err(e) = file.write_all(&response.data).await {
-                            println!("Failed to write to file: {}", e);
+                if request.cancel {
+                    state
+                        .uploads
+                        .cancelled_by_peer(&mut state.transfers, peer, &request);
+                    behaviour.respond(channel, Err(FileError::Cancelled));
+                    return Ok(());
+                }
+                let share = if from_store {
+                    state.store.share()
+                } else {
+                    &state.share
+                };
+                // Rules are keyed by the listed name, so other spellings and symlinks get the same answer
+                let name = share
+                    .canonical_name(&request.filename)
+                    .unwrap_or_else(|| request.filename.clone());
+                match state.policy.check(&peer, &name) {
+                    Decision::Allow => {
+                        let mut response = behaviour
+                            .process_req(
+                                peer,
+                                request,
+                                share,
+                                &mut state.catalogue,
+                                &mut state.transfers,
+                                &mut state.uploads,
+                            )
+                            .await;
+                        // The peer saves the file under its name, not where our store keeps it
+                        if let (true, Ok(chunk)) = (from_store, response.as_mut()) {
+                            if let Some((_, name)) = chunk.filename.split_once('/') {
+                                chunk.filename = name.to_string();
+                            }
+                        }
+                        let bytes = response.as_ref().map_or(0, FileChunk::size);
+                        let wait = state.throttle.reserve(
+                            Direction::Upload,
+                            peer,
+                            state.nicknames.get(&peer),
+                            bytes,
+                        );
+                        if wait.is_zero() {
+                            behaviour.respond(channel, response);
                         } else {
-                            println!("Saved file to: {:?}", filename);
+                            state
+                                .throttle
+                                .hold(wait, Held::Response { channel, response });
                         }
                     }
-                    Err(e) => {
-                        println!("Failed to create file: {}", e);
+                    Decision::Deny(reason) => {
+                        behaviour.refuse(peer, &request, channel, FileError::Refused(reason));
+                    }
+                    Decision::Prompt => {
+                        if let Some(id) = state.policy.add_prompt(peer, &request.filename) {
+                            println!(
+                                "{} wants to bytestream {} from you",
+                                state.nicknames.get(&peer).unwrap_or(&peer.to_string()),
+                                request.filename
+                            );
+                            println!("Type /approve {} or /reject {}", id, id);
+                        }
+                        behaviour.refuse(peer, &request, channel, FileError::AwaitingApproval);
                     }
                 }
-                println!("Response: {:?}", response);
+            }
+            request_response::Message::Response {
+                request_id,
+                response,
+            } => {
+                let bytes = response.as_ref().map_or(0, FileChunk::size);
+                let wait = state.throttle.reserve(
+                    Direction::Download,
+                    peer,
+                    state.nicknames.get(&peer),
+                    bytes,
+                );
+                if wait.is_zero() {
+                    handle_chunk(swarm, request_id, response, state).await?;
+                } else {
+                    state.throttle.hold(
+                        wait,
+                        Held::Chunk {
+                            request_id,
+                            response,
+                        },
+                    );
+                }
             }
         },
         SendFileBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
             peer,
-            request_id: _,
+            request_id,
             error,
         }) => {
             println!("Failed to ByteBeam to peer: {:?}: {:?}", peer, error);
+            if state.fetches.owns(&request_id) {
+                state
+                    .fetches
+                    .handle_failure(
+                        &mut swarm.behaviour_mut().request_response,
+                        &mut state.transfers,
+                        request_id,
+                    )
+                    .await?;
+            } else {
+                state.downloads.handle_failure(
+                    &mut swarm.behaviour_mut().request_response,
+                    &mut state.transfers,
+                    request_id,
+                );
+            }
         }
         SendFileBehaviourEvent::RequestResponse(request_response::Event::InboundFailure {
             peer,
@@ -260,11 +609,73 @@ async fn handle_send_file_event(
                 peer, error
             );
         }
+        // Chunks are acknowledged individually, completion is reported by process_req
         SendFileBehaviourEvent::RequestResponse(request_response::Event::ResponseSent {
-            peer,
-            request_id: _,
-        }) => {
-            println!("ByteBeam was successful to peer: {:?}", peer);
+            ..
+        }) => {}
+    }
+    Ok(())
+}
+
+// Handle a chunk we received, as part of either a multi-source fetch or a download
+async fn handle_chunk(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    request_id: OutboundRequestId,
+    response: FileResponse,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    if state.fetches.owns(&request_id) {
+        state
+            .fetches
+            .handle_response(
+                &mut swarm.behaviour_mut().request_response,
+                &mut state.transfers,
+                &mut state.store,
+                request_id,
+                response,
+                state.downloads.collision(),
+            )
+            .await?;
+    } else {
+        state
+            .downloads
+            .handle_response(
+                &mut swarm.behaviour_mut().request_response,
+                &mut state.transfers,
+                &mut state.store,
+                request_id,
+                response,
+            )
+            .await?;
+    }
+
+    // Content new to the store can now be fetched from us by hash
+    state.search.provide(
+        &mut swarm.behaviour_mut().kademlia,
+        &state.store.take_saved(),
+    );
+    Ok(())
+}
+
+// Send and handle the file traffic the bandwidth limits held back once its turn comes
+async fn release_held(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    for held in state.throttle.take_due() {
+        match held {
+            Held::Response { channel, response } => {
+                swarm
+                    .behaviour_mut()
+                    .request_response
+                    .respond(channel, response);
+            }
+            Held::Chunk {
+                request_id,
+                response,
+            } => {
+                handle_chunk(swarm, request_id, response, state).await?;
+            }
         }
     }
     Ok(())
@@ -275,11 +686,12 @@ async fn handle_direct_message_event(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     direct_message_event: DirectMessageBehaviourEvent,
 ) -> Result<(), Box<dyn Error>> {
-    match direct_message_event {
-        DirectMessageBehaviourEvent::RequestResponse(request_response::Event::Message {
-            message,
-            ..
-        }) => match message {
+    if let DirectMessageBehaviourEvent::RequestResponse(request_response::Event::Message {
+        message,
+        ..
+    }) = direct_message_event
+    {
+        match message {
             request_response::Message::Request {
                 request, channel, ..
             } => {
@@ -294,59 +706,346 @@ async fn handle_direct_message_event(
                 let message = response.0;
                 println!("{}", message);
             }
+        }
+    }
+    Ok(())
+}
+
+// Handle file list requests and print the catalogues peers send back
+async fn handle_file_list_event(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    file_list_event: FileListBehaviourEvent,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    match file_list_event {
+        FileListBehaviourEvent::RequestResponse(request_response::Event::Message {
+            peer,
+            message,
+        }) => match message {
+            request_response::Message::Request {
+                request, channel, ..
+            } => {
+                let response = filelist::list_files(
+                    peer,
+                    &request,
+                    &state.share,
+                    &mut state.catalogue,
+                    &state.policy,
+                )
+                .await;
+                FileListBehaviour::handle_request(
+                    &mut swarm.behaviour_mut().file_list,
+                    peer,
+                    channel,
+                    response,
+                )?;
+            }
+            request_response::Message::Response {
+                request_id,
+                response,
+            } => {
+                // The manifest of a directory we asked for, its files are fetched one by one
+                if let Some(dir) = state.downloads.take_directory(&request_id) {
+                    match response {
+                        Ok(files) => state.downloads.start_directory(
+                            &mut swarm.behaviour_mut().request_response,
+                            &mut state.transfers,
+                            peer,
+                            &dir,
+                            files,
+                        )?,
+                        Err(e) => {
+                            println!("Could not fetch directory {} from {}: {}", dir, peer, e)
+                        }
+                    }
+                    return Ok(());
+                }
+                match (state.search.take_listing(&request_id), response) {
+                    // Lists requested by a search only show the matching files
+                    (Some(term), Ok(files)) => {
+                        for file in files.iter().filter(|file| search::matches(&term, file)) {
+                            println!(
+                                "Peer {} has {} ({})",
+                                peer,
+                                file.name,
+                                catalogue::format_size(file.size)
+                            );
+                        }
+                    }
+                    (Some(_), Err(_)) => {}
+                    (None, Ok(files)) if files.is_empty() => {
+                        println!("Peer {} is not sharing any files", peer);
+                    }
+                    (None, Ok(files)) => {
+                        println!("Files shared by {}:", peer);
+                        for file in files {
+                            println!(
+                                "  {}  {}  {}  {}",
+                                file.name,
+                                catalogue::format_size(file.size),
+                                catalogue::format_timestamp(file.modified),
+                                // The peer may send anything as the hash
+                                file.hash.get(..16).unwrap_or(&file.hash)
+                            );
+                        }
+                    }
+                    (None, Err(e)) => {
+                        println!("Could not list files of {}: {}", peer, e);
+                    }
+                }
+            }
         },
+        // Failures of lists requested by a search are not worth reporting
+        FileListBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+        }) if state.search.take_listing(&request_id).is_none() => {
+            state.downloads.take_directory(&request_id);
+            println!("Failed to list files of peer: {:?}: {:?}", peer, error);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Handle incoming file offers, answers to our requests and failures to deliver our own
+async fn handle_offer_event(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    offer_event: OfferBehaviourEvent,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    match offer_event {
+        OfferBehaviourEvent::RequestResponse(request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+        }) => {
+            // A bytestream waiting for approval can go ahead or be given up
+            match &request {
+                OfferMessage::Approved { filename } => {
+                    // Only the peer we asked can approve the download
+                    if let Err(e) = state.downloads.resume_file(
+                        &mut swarm.behaviour_mut().request_response,
+                        &mut state.transfers,
+                        Some(peer),
+                        filename,
+                    ) {
+                        eprintln!("{}", e);
+                    }
+                }
+                OfferMessage::Denied { filename } => {
+                    state
+                        .downloads
+                        .drop_paused(
+                            &mut swarm.behaviour_mut().request_response,
+                            &mut state.transfers,
+                            peer,
+                            filename,
+                            "peer denied the request",
+                        )
+                        .await?;
+                }
+                _ => {}
+            }
+            OfferBehaviour::handle_request(
+                &mut swarm.behaviour_mut().offer,
+                peer,
+                request,
+                channel,
+                &mut state.offers,
+            )?;
+        }
+        OfferBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+            peer,
+            error,
+            ..
+        }) => {
+            println!("Failed to reach peer {:?} with offer: {:?}", peer, error);
+        }
         _ => {}
     }
     Ok(())
 }
 
+// State of the chat session, shared between the event handlers and the commands
+pub struct ChatState {
+    pub nickname: String,
+    pub keypair: Keypair,
+    pub self_peer_id: PeerId,
+    pub pending_queries: HashMap<QueryId, (PeerId, String, String)>,
+    pub nicknames: NicknameCache,
+    pub rooms: Rooms,
+    pub downloads: Downloads,
+    pub uploads: Uploads,
+    pub transfers: Transfers,
+    pub share: ShareRoot,
+    pub catalogue: Catalogue,
+    pub search: Search,
+    pub offers: Offers,
+    pub policy: ServePolicy,
+    pub fetches: Fetches,
+    pub throttle: Throttle,
+    pub watcher: ShareWatcher,
+    pub store: BlobStore,
+    // The settings we started with, shown by /config
+    pub settings: Settings,
+}
+
 // Main chat loop function
-pub async fn start_chat(nickname: String) -> Result<(), Box<dyn Error>> {
-    let mut swarm = initialize_swarm()?;
-    let self_peer_id = swarm.local_peer_id().clone();
-    let topic = gossipsub::IdentTopic::new("chat");
-    let mut pending_queries: HashMap<QueryId, (PeerId, String)> = HashMap::new();
+pub async fn start_chat(settings: Settings) -> Result<(), Box<dyn Error>> {
+    // Load the persistent identity so the peer id survives restarts
+    let keypair = identity::load_or_generate(&settings.identity)?;
+    let mut swarm = initialize_swarm(keypair.clone(), &settings)?;
+    let directories = &settings.directories;
+    let share = ShareRoot::with_aliases(&directories.share, directories.shares.clone());
+    let watcher = ShareWatcher::new(&share.roots());
+    let mut state = ChatState {
+        nickname: settings.nickname.clone(),
+        keypair,
+        self_peer_id: *swarm.local_peer_id(),
+        pending_queries: HashMap::new(),
+        nicknames: NicknameCache::default(),
+        rooms: Rooms::default(),
+        downloads: Downloads::new(
+            directories.downloads.clone(),
+            settings.chunk_size,
+            settings.max_downloads,
+            settings.collision,
+            &settings.compression,
+        ),
+        uploads: Uploads::new(settings.max_uploads),
+        transfers: Transfers::default(),
+        share,
+        catalogue: Catalogue::default(),
+        search: Search::default(),
+        offers: Offers::default(),
+        policy: ServePolicy::load(&settings.data_dir)?,
+        fetches: Fetches::new(
+            directories.downloads.clone(),
+            settings.chunk_size,
+            &settings.compression,
+        ),
+        throttle: Throttle::new(Limits::load(&settings.data_dir)?.with_overrides(&settings.limits)),
+        watcher,
+        store: BlobStore::new(&directories.store),
+        settings: settings.clone(),
+    };
+    state.downloads.load_partial(&mut state.transfers);
+
+    // Announce our shared files so peers can find them with /search
+    let files = state.catalogue.scan(&state.share).await;
+    state.search.announce(
+        &mut swarm.behaviour_mut().kademlia,
+        &public_files(&state, &files),
+    );
+    state.watcher.update(files);
+    state
+        .search
+        .provide(&mut swarm.behaviour_mut().kademlia, &state.store.hashes());
     let mut stdin = io::BufReader::new(io::stdin()).lines();
 
-    println!("Enter chat messages one line at a time:");
+    handle_bootstrap(
+        &mut swarm,
+        &settings.bootstrap,
+        &state.nickname,
+        &state.self_peer_id,
+    );
+    if settings.output == OutputMode::Interactive {
+        println!("Enter chat messages one line at a time:");
+    }
 
     loop {
+        let due = state.throttle.next_due();
+        let retry = [state.downloads.next_retry(), state.fetches.next_retry()]
+            .into_iter()
+            .flatten()
+            .min();
+        let rescan = state.watcher.rescan_at();
         select! {
             Ok(Some(line)) = stdin.next_line() => {
                 if line.starts_with("/") {
-                    if let Err(err) = commands::process_command(line, &mut swarm, self_peer_id) {
+                    if let Err(err) = commands::process_command(line, &mut swarm, &mut state).await {
                         println!("Error processing command: {:?}", err);
                     }
                 } else {
-                    handle_publish_message(&mut swarm, &topic, &line);
+                    handle_publish_message(&mut swarm, &state.rooms.active_topic(), &line, &state.nickname, &state.self_peer_id);
                 }
             }
 
-            // Handle swarm events
+            // Handle swarm events, an error in one is reported without ending the session
             event = swarm.select_next_some() => match event {
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
-                    handle_mdns_discovered(&mut swarm, peers, &nickname, &self_peer_id);
+                    handle_mdns_discovered(&mut swarm, peers, &state.nickname, &state.self_peer_id, &mut state.downloads, &mut state.transfers);
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
-                    handle_mdns_expired(&mut swarm, peers);
+                    handle_mdns_expired(&mut swarm, peers, &mut state.rooms);
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
+                    propagation_source: _,
                     message_id: _,
                     message,
                 })) => {
-                    handle_gossipsub_message(&mut swarm, peer_id, message.data, &mut pending_queries);
+                    handle_gossipsub_message(&mut swarm, message, &mut state.pending_queries, &state.nicknames);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                    state.rooms.peer_subscribed(peer_id, &topic);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                    state.rooms.peer_unsubscribed(peer_id, &topic);
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result, .. })) => {
-                    process_outbound_query(id, result, &mut pending_queries);
+                    if let Err(e) = process_outbound_query(&mut swarm, id, result, &mut state) {
+                        eprintln!("Error handling a Kademlia query: {}", e);
+                    }
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::RequestResponse(send_file_event)) => {
-                    handle_send_file_event(&mut swarm, send_file_event).await?;
+                    if let Err(e) = handle_send_file_event(&mut swarm, send_file_event, &mut state).await {
+                        eprintln!("Error handling a ByteBeam event: {}", e);
+                    }
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::DirectMessage(direct_message_event)) => {
-                    handle_direct_message_event(&mut swarm, direct_message_event).await?;
+                    if let Err(e) = handle_direct_message_event(&mut swarm, direct_message_event).await {
+                        eprintln!("Error handling a direct message: {}", e);
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Offer(offer_event)) => {
+                    if let Err(e) = handle_offer_event(&mut swarm, offer_event, &mut state).await {
+                        eprintln!("Error handling an offer: {}", e);
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::FileList(file_list_event)) => {
+                    if let Err(e) = handle_file_list_event(&mut swarm, file_list_event, &mut state).await {
+                        eprintln!("Error handling a file list: {}", e);
+                    }
                 }
                 _ => {}
+            },
+
+            // Release file traffic held back by the bandwidth limits
+            _ = time::sleep_until(due.unwrap_or_else(time::Instant::now)), if due.is_some() => {
+                if let Err(e) = release_held(&mut swarm, &mut state).await {
+                    eprintln!("Error handling held file traffic: {}", e);
+                }
+            }
+
+            // Ask busy peers again for the downloads and fetches they turned away
+            _ = time::sleep_until(retry.unwrap_or_else(Instant::now).into()), if retry.is_some() => {
+                state.downloads.retry(&mut swarm.behaviour_mut().request_response, &mut state.transfers);
+                if let Err(e) = state.fetches.retry(&mut swarm.behaviour_mut().request_response) {
+                    eprintln!("Error handling a fetch retry: {}", e);
+                }
+            }
+
+            // Rescan the ByteBeam once it has settled after a change
+            _ = state.watcher.changed() => {
+                state.watcher.touch();
+            }
+            _ = time::sleep_until(rescan.unwrap_or_else(time::Instant::now)), if rescan.is_some() => {
+                state.watcher.start_rescan();
+                handle_share_changed(&mut swarm, &mut state).await;
             }
         }
     }
@@ -1,18 +1,33 @@
+use crate::catalogue;
+use crate::chatroom::ChatState;
+use crate::compression;
+use crate::config;
 use crate::directmessage::DirectMessage;
+use crate::identity;
+use crate::limits;
 use crate::network::ChatBehaviour;
-use crate::sendfile::FileRequest;
+use crate::nicknames::NicknameCache;
+use crate::offer::{FileOffer, OfferMessage};
+use crate::policy::{ServeMode, Visibility};
+use crate::rooms::Rooms;
+use crate::sendfile::{Collision, Downloads, Priority, Transfers};
 
 use libp2p::PeerId;
 use regex::Regex;
+use std::collections::BTreeSet;
 use std::error::Error;
-use std::str::FromStr;
+use std::path::Path;
 
 // Processes commands entered by the user
-pub fn process_command(
+pub async fn process_command(
     line: String,
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
-    self_peer_id: PeerId,
+    state: &mut ChatState,
 ) -> Result<(), Box<dyn Error>> {
+    let self_peer_id = state.self_peer_id;
+    let nicknames = &state.nicknames;
+    let rooms = &mut state.rooms;
+
     // Get the command and arguments
     let args = Regex::new(r#""[^"]*"|\S+"#)
         .unwrap()
@@ -24,27 +39,214 @@ pub fn process_command(
 
     match cmd {
         "/bytestream" => {
+            if args.len() != 3 && args.len() != 4 {
+                println!("Usage: /bytestream <peer_id|nickname> <filename> [low|normal|high]");
+                return Ok(());
+            }
+            process_req(
+                swarm,
+                args,
+                nicknames,
+                &mut state.downloads,
+                &mut state.transfers,
+            );
+        }
+        "/bytestreamdir" => {
+            if args.len() != 3 {
+                println!("Usage: /bytestreamdir <peer_id|nickname> <directory>");
+                return Ok(());
+            }
+            process_dir_req(swarm, &args, state)?;
+        }
+        "/bytebeam" => {
+            if args.len() != 3 {
+                println!("Usage: /bytebeam <peer_id|nickname> <filename>");
+                return Ok(());
+            }
+            process_beam(swarm, &args, state).await?;
+        }
+        "/offers" => {
+            process_offers(state);
+        }
+        "/accept" | "/decline" => {
+            let Some(id) = args.get(1).and_then(|id| id.parse().ok()) else {
+                println!("Usage: {} <offer_id>", cmd);
+                return Ok(());
+            };
+            process_offer_reply(swarm, cmd == "/accept", id, state)?;
+        }
+        "/policy" => {
+            process_policy(&args, state)?;
+        }
+        "/visibility" => {
             if args.len() != 3 {
-                println!("Usage: /bytestream <peer_id> <filename>");
+                println!("Usage: /visibility <filename> <public|hidden|peer,...>");
+                return Ok(());
+            }
+            process_visibility(&args, state)?;
+        }
+        "/approve" | "/reject" => {
+            let Some(id) = args.get(1).and_then(|id| id.parse().ok()) else {
+                println!("Usage: {} <request_id>", cmd);
+                return Ok(());
+            };
+            process_approval(swarm, cmd == "/approve", id, state)?;
+        }
+        "/resume" => {
+            process_resume(swarm, &args, &mut state.downloads, &mut state.transfers)?;
+        }
+        "/conflict" => match args.get(1).map(|s| s.parse::<Collision>()) {
+            None => println!(
+                "Existing files are handled with: {}",
+                state.downloads.collision()
+            ),
+            Some(Ok(collision)) => {
+                state.downloads.set_collision(collision);
+                println!("Existing files will be handled with: {}", collision);
+            }
+            Some(Err(e)) => eprintln!("{}", e),
+        },
+        "/compression" => match args.get(1).map(|s| compression::parse_setting(s)) {
+            None if state.downloads.compression().is_empty() => {
+                println!("Compression is off")
+            }
+            None => println!(
+                "Peers may compress chunks with: {}",
+                state.downloads.compression()[0]
+            ),
+            Some(Ok(setting)) => {
+                state.downloads.set_compression(&setting);
+                state.fetches.set_compression(&setting);
+                println!("Compression setting updated");
+            }
+            Some(Err(e)) => eprintln!("{}", e),
+        },
+        "/limit" => {
+            process_limit(&args, state)?;
+        }
+        "/config" => {
+            process_config(state)?;
+        }
+        "/cancel" => {
+            let Some(id) = args.get(1).and_then(|id| id.parse().ok()) else {
+                println!("Usage: /cancel <transfer_id>");
+                return Ok(());
+            };
+            process_cancel(swarm, id, state).await?;
+        }
+        "/priority" => {
+            let (Some(id), Some(priority)) = (
+                args.get(1).and_then(|id| id.parse().ok()),
+                args.get(2).and_then(|p| p.parse::<Priority>().ok()),
+            ) else {
+                println!("Usage: /priority <transfer_id> <low|normal|high>");
                 return Ok(());
+            };
+            if state.downloads.set_priority(id, priority) {
+                println!("Download #{} now has {} priority", id, priority);
+            } else {
+                eprintln!("No unfinished download #{}", id);
+            }
+        }
+        "/queue" => {
+            process_queue(swarm, &args, state);
+        }
+        "/transfers" => {
+            state.uploads.expire(&mut state.transfers);
+            process_transfers(&state.transfers);
+        }
+        "/ls" => {
+            if args.len() != 2 {
+                println!("Usage: /ls <peer_id|nickname>");
+                return Ok(());
+            }
+            process_ls(swarm, &args[1], nicknames)?;
+        }
+        "/search" => {
+            if args.len() < 2 {
+                println!("Usage: /search <term|hash>");
+                return Ok(());
+            }
+            let term = args[1..].join(" ");
+            match state
+                .search
+                .start(&mut swarm.behaviour_mut().kademlia, &term)
+            {
+                Ok(_) => println!("Searching for '{}'...", term),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        "/fetch" => {
+            if args.len() != 2 {
+                println!("Usage: /fetch <hash>");
+                return Ok(());
+            }
+            match state
+                .fetches
+                .start(&mut swarm.behaviour_mut().kademlia, &args[1])
+            {
+                Ok(_) => println!("Looking for peers that have {}...", &args[1]),
+                Err(e) => eprintln!("{}", e),
             }
-            process_req(swarm, args)?;
         }
         "/whisper" => {
             if args.len() < 3 {
-                println!("Usage: /whisper <peer_id> <message>");
+                println!("Usage: /whisper <peer_id|nickname> <message>");
+                return Ok(());
+            }
+            process_whisper(swarm, args, self_peer_id, nicknames)?;
+        }
+        "/who" => {
+            if args.len() > 2 {
+                println!("Usage: /who [prefix]");
                 return Ok(());
             }
-            process_whisper(swarm, args, self_peer_id)?;
+            process_who(args.get(1).map_or("", String::as_str), nicknames);
         }
         "/id" => {
             println!("Your peer id: {}", self_peer_id);
         }
+        "/join" => {
+            if args.len() != 2 {
+                println!("Usage: /join <room>");
+                return Ok(());
+            }
+            process_join(swarm, &args[1], rooms)?;
+        }
+        "/leave" => {
+            if args.len() != 2 {
+                println!("Usage: /leave <room>");
+                return Ok(());
+            }
+            process_leave(swarm, &args[1], rooms)?;
+        }
+        "/switch" => {
+            if args.len() != 2 {
+                println!("Usage: /switch <room>");
+                return Ok(());
+            }
+            match rooms.switch(&args[1]) {
+                Ok(_) => println!("Now chatting in #{}", rooms.active()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        "/rooms" => {
+            process_rooms(rooms);
+        }
+        "/fingerprint" => {
+            println!(
+                "Your key fingerprint: {}",
+                identity::fingerprint(&state.keypair)
+            );
+        }
+        "/regenkey" => {
+            process_regenkey(&state.settings.identity)?;
+        }
         _ => {
             println!("Invalid command: {}", cmd);
         }
     }
-    Ok({})
+    Ok(())
 }
 
 // Handles the /whisper command for sending a private message
@@ -52,8 +254,9 @@ fn process_whisper(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     args: Vec<String>,
     self_peer_id: PeerId,
+    nicknames: &NicknameCache,
 ) -> Result<(), Box<dyn Error + 'static>> {
-    let other_peer_id = match PeerId::from_str(&args[1]) {
+    let other_peer_id = match nicknames.resolve(&args[1]) {
         Ok(peer_id) => peer_id,
         Err(err) => {
             eprintln!("Peer '{}' does not exist. Err: {}", &args[1], err);
@@ -79,8 +282,50 @@ fn process_whisper(
 fn process_req(
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
     args: Vec<String>,
+    nicknames: &NicknameCache,
+    downloads: &mut Downloads,
+    transfers: &mut Transfers,
+) {
+    let other_peer_id = match nicknames.resolve(&args[1]) {
+        Ok(peer_id) => peer_id,
+        Err(err) => {
+            eprintln!("Peer '{}' does not exist. Err: {}", &args[1], err);
+            return;
+        }
+    };
+    let priority = match args.get(3).map(|p| p.parse::<Priority>()) {
+        None => Priority::Normal,
+        Some(Ok(priority)) => priority,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // Request the first chunk once a slot is free, the rest follow as each one arrives
+    if let Err(e) = downloads.start(
+        &mut swarm.behaviour_mut().request_response,
+        transfers,
+        other_peer_id,
+        &args[2],
+        priority,
+    ) {
+        eprintln!("{}", e);
+        return;
+    }
+    println!(
+        "Sent bytestream for {} to peer: {}",
+        &args[2], other_peer_id
+    );
+}
+
+// Handles the /bytestreamdir command for requesting a whole directory
+fn process_dir_req(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    args: &[String],
+    state: &mut ChatState,
 ) -> Result<(), Box<dyn Error>> {
-    let other_peer_id = match PeerId::from_str(&args[1]) {
+    let other_peer_id = match state.nicknames.resolve(&args[1]) {
         Ok(peer_id) => peer_id,
         Err(err) => {
             eprintln!("Peer '{}' does not exist. Err: {}", &args[1], err);
@@ -88,14 +333,548 @@ fn process_req(
         }
     };
 
-    // Send the request
-    swarm
+    // Ask for the manifest first, the files are requested once it arrives
+    let dir = args[2].trim_end_matches('/').to_string();
+    let request_id = swarm
         .behaviour_mut()
-        .request_response
-        .send_req(other_peer_id, FileRequest(args[2].to_string()))?;
+        .file_list
+        .send_req(other_peer_id, Some(dir.clone()))?;
+    state.downloads.add_directory(request_id, dir.clone());
     println!(
-        "Sent bytestream for {} to peer: {}",
+        "Sent bytestream for directory {} to peer: {}",
+        dir, other_peer_id
+    );
+    Ok(())
+}
+
+// Handles the /bytebeam command for offering one of our shared files to a peer
+async fn process_beam(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    args: &[String],
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    let other_peer_id = match state.nicknames.resolve(&args[1]) {
+        Ok(peer_id) => peer_id,
+        Err(err) => {
+            eprintln!("Peer '{}' does not exist. Err: {}", &args[1], err);
+            return Ok(());
+        }
+    };
+
+    // Only files the peer could fetch from our ByteBeam can be offered
+    let path = match state.share.resolve(&state.self_peer_id, &args[2]) {
+        Ok(path) if path.is_file() => path,
+        _ => {
+            eprintln!("{} is not a file in your ByteBeam", &args[2]);
+            return Ok(());
+        }
+    };
+    let (size, _, hash) = state.catalogue.hash(&path).await?;
+
+    // The peer may fetch what we offered whatever the serving policy says
+    let name = state
+        .share
+        .canonical_name(&args[2])
+        .unwrap_or_else(|| args[2].clone());
+    state.policy.approve(other_peer_id, &name);
+
+    swarm.behaviour_mut().offer.send_req(
+        other_peer_id,
+        OfferMessage::Offer(FileOffer {
+            sender: state.nickname.clone(),
+            filename: args[2].to_string(),
+            size,
+            hash,
+        }),
+    )?;
+    println!(
+        "Offered {} to peer: {}, it will be sent once they accept",
         &args[2], other_peer_id
     );
     Ok(())
 }
+
+// Handles the /offers command for listing offers waiting for an answer
+fn process_offers(state: &ChatState) {
+    let mut any = false;
+    for (id, (peer_id, offer)) in state.offers.iter() {
+        any = true;
+        println!(
+            "  {}: {} ({}) from {} ({})",
+            id,
+            offer.filename,
+            catalogue::format_size(offer.size),
+            offer.sender,
+            peer_id
+        );
+    }
+    if !any {
+        println!("No pending offers");
+    }
+}
+
+// Handles /accept and /decline for a received offer
+fn process_offer_reply(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    accept: bool,
+    id: u64,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    let Some((peer_id, offer)) = state.offers.take(id) else {
+        eprintln!("No pending offer with id {}", id);
+        return Ok(());
+    };
+
+    if accept {
+        // Accepting pulls the file like /bytestream, so it gets resume and verification too
+        if let Err(e) = state.downloads.start(
+            &mut swarm.behaviour_mut().request_response,
+            &mut state.transfers,
+            peer_id,
+            &offer.filename,
+            Priority::Normal,
+        ) {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+        println!("Accepted {} from {}", offer.filename, offer.sender);
+    } else {
+        swarm.behaviour_mut().offer.send_req(
+            peer_id,
+            OfferMessage::Declined {
+                filename: offer.filename.clone(),
+            },
+        )?;
+        println!("Declined {} from {}", offer.filename, offer.sender);
+    }
+    Ok(())
+}
+
+// Handles the /policy command for showing or changing who may fetch our files
+fn process_policy(args: &[String], state: &mut ChatState) -> Result<(), Box<dyn Error>> {
+    let policy = &mut state.policy;
+    match (args.get(1).map(String::as_str), args.get(2)) {
+        (None, _) => {
+            println!("Serving mode: {:?}", policy.mode);
+            println!("Allowed: {:?}", policy.allow);
+            println!("Denied: {:?}", policy.deny);
+            for (filename, visibility) in &policy.visibility {
+                println!("  {}: {}", filename, visibility);
+            }
+            for (id, (peer_id, filename)) in policy.prompts() {
+                println!("  {}: {} wants {}", id, peer_id, filename);
+            }
+            return Ok(());
+        }
+        (Some("mode"), Some(mode)) => match mode.parse::<ServeMode>() {
+            Ok(mode) => policy.mode = mode,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Ok(());
+            }
+        },
+        // Lists hold peer ids, as anyone can publish a nickname
+        (Some("allow"), Some(peer)) => {
+            let Some(peer) = resolve_peer(&state.nicknames, peer) else {
+                return Ok(());
+            };
+            policy.deny.remove(&peer);
+            policy.allow.insert(peer);
+        }
+        (Some("deny"), Some(peer)) => {
+            let Some(peer) = resolve_peer(&state.nicknames, peer) else {
+                return Ok(());
+            };
+            policy.allow.remove(&peer);
+            policy.deny.insert(peer);
+        }
+        (Some("forget"), Some(peer)) => {
+            let peer = state
+                .nicknames
+                .resolve(peer)
+                .map_or_else(|_| peer.clone(), |peer_id| peer_id.to_string());
+            policy.allow.remove(&peer);
+            policy.deny.remove(&peer);
+        }
+        _ => {
+            println!("Usage: /policy [mode <auto|prompt|allowlist|denylist> | allow|deny|forget <peer_id|nickname>]");
+            return Ok(());
+        }
+    }
+    policy.save(&state.settings.data_dir)?;
+    // Rescan so the DHT only holds the files that are public now
+    state.watcher.touch();
+    println!("Serving policy updated");
+    Ok(())
+}
+
+// Handles the /config command for showing the settings in effect, in config file format
+fn process_config(state: &mut ChatState) -> Result<(), Box<dyn Error>> {
+    let mut config = state.settings.to_config();
+
+    // Commands may have changed some settings since startup
+    config.nickname = Some(state.nickname.clone());
+    config.transfers.on_conflict = Some(state.downloads.collision().to_string());
+    config.transfers.max_downloads = Some(state.downloads.slots().1);
+    config.transfers.max_uploads = Some(state.uploads.slots(&mut state.transfers).1);
+    config.transfers.compression = Some(compression::format_setting(state.downloads.compression()));
+    config.limits = config::LimitsConfig::from_limits(state.throttle.limits());
+
+    match &state.settings.config_file {
+        Some(path) => println!("# Settings in effect, read from {:?}", path),
+        None => println!(
+            "# Settings in effect, no config file at {:?}",
+            config::config_path()
+        ),
+    }
+    print!("{}", config.to_toml()?);
+    Ok(())
+}
+
+// Handles the /limit command for showing and changing the bandwidth limits on file traffic
+fn process_limit(args: &[String], state: &mut ChatState) -> Result<(), Box<dyn Error>> {
+    let mut limits = state.throttle.limits().clone();
+    let chunk_size = state.settings.chunk_size;
+    let parse = |rate: &str| limits::parse_limit(rate, chunk_size);
+    let usage = "Usage: /limit [up|down <rate|off> | peer [<peer_id|nickname>] <rate|off>]";
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+    let result = match args[1..] {
+        [] => {
+            println!("Upload: {}", limits::format_rate(limits.upload));
+            println!("Download: {}", limits::format_rate(limits.download));
+            println!("Each peer: {}", limits::format_rate(limits.per_peer));
+            for (peer, rate) in &limits.peers {
+                println!("  {}: {}", peer, limits::format_rate(Some(*rate)));
+            }
+            return Ok(());
+        }
+        ["up", rate] => parse(rate).map(|rate| limits.upload = rate),
+        ["down", rate] => parse(rate).map(|rate| limits.download = rate),
+        ["peer", rate] => parse(rate).map(|rate| limits.per_peer = rate),
+        ["peer", peer, rate] => parse(rate).map(|rate| match rate {
+            Some(rate) => {
+                limits.peers.insert(peer.to_string(), rate);
+            }
+            None => {
+                limits.peers.remove(peer);
+            }
+        }),
+        _ => {
+            println!("{}", usage);
+            return Ok(());
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        return Ok(());
+    }
+    limits.save(&state.settings.data_dir)?;
+    state.throttle.set_limits(limits);
+    println!("Bandwidth limits updated");
+    Ok(())
+}
+
+// Handles the /visibility command for choosing who can see a shared file
+fn process_visibility(args: &[String], state: &mut ChatState) -> Result<(), Box<dyn Error>> {
+    // Rules are looked up by the name the file is listed under
+    let filename = state
+        .share
+        .canonical_name(&args[1])
+        .unwrap_or_else(|| args[1].clone());
+    match args[2].as_str() {
+        "public" => {
+            state.policy.visibility.remove(&filename);
+        }
+        "hidden" => {
+            state.policy.visibility.insert(filename, Visibility::Hidden);
+        }
+        peers => {
+            let mut ids = BTreeSet::new();
+            for peer in peers.split(',') {
+                let Some(peer_id) = resolve_peer(&state.nicknames, peer) else {
+                    return Ok(());
+                };
+                ids.insert(peer_id);
+            }
+            state
+                .policy
+                .visibility
+                .insert(filename, Visibility::Only(ids));
+        }
+    }
+    state.policy.save(&state.settings.data_dir)?;
+    state.watcher.touch();
+    println!("Visibility of {} updated", &args[1]);
+    Ok(())
+}
+
+// Resolves a peer id or nickname for the serving policy, printing why if it cannot
+fn resolve_peer(nicknames: &NicknameCache, peer: &str) -> Option<String> {
+    match nicknames.resolve(peer) {
+        Ok(peer_id) => Some(peer_id.to_string()),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    }
+}
+
+// Handles /approve and /reject for a bytestream waiting on the serving policy
+fn process_approval(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    approve: bool,
+    id: u64,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    let Some((peer_id, filename)) = state.policy.take_prompt(id) else {
+        eprintln!("No pending request with id {}", id);
+        return Ok(());
+    };
+
+    // The peer's download is paused until it hears back from us, under the name it asked for
+    if approve {
+        let name = state
+            .share
+            .canonical_name(&filename)
+            .unwrap_or_else(|| filename.clone());
+        state.policy.approve(peer_id, &name);
+        swarm.behaviour_mut().offer.send_req(
+            peer_id,
+            OfferMessage::Approved {
+                filename: filename.clone(),
+            },
+        )?;
+        println!("Approved {} for peer: {}", filename, peer_id);
+    } else {
+        swarm.behaviour_mut().offer.send_req(
+            peer_id,
+            OfferMessage::Denied {
+                filename: filename.clone(),
+            },
+        )?;
+        println!("Rejected {} for peer: {}", filename, peer_id);
+    }
+    Ok(())
+}
+
+// Handles the /resume command for listing or retrying unfinished downloads
+fn process_resume(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    args: &[String],
+    downloads: &mut Downloads,
+    transfers: &mut Transfers,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(filename) = args.get(1) {
+        if let Err(e) = downloads.resume_file(
+            &mut swarm.behaviour_mut().request_response,
+            transfers,
+            None,
+            filename,
+        ) {
+            eprintln!("{}", e);
+        }
+        return Ok(());
+    }
+
+    let unfinished = downloads.unfinished();
+    if unfinished.is_empty() {
+        println!("No unfinished downloads");
+        return Ok(());
+    }
+    println!("Unfinished downloads (/resume <filename> to retry now):");
+    for line in unfinished {
+        println!("  {}", line);
+    }
+    Ok(())
+}
+
+// Handles the /cancel command for stopping a transfer on both ends
+async fn process_cancel(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    id: u64,
+    state: &mut ChatState,
+) -> Result<(), Box<dyn Error>> {
+    let behaviour = &mut swarm.behaviour_mut().request_response;
+    let cancelled = state.uploads.cancel(&mut state.transfers, id)
+        || state.fetches.cancel(behaviour, &mut state.transfers, id)?
+        || state
+            .downloads
+            .cancel(behaviour, &mut state.transfers, id)
+            .await?;
+    if !cancelled {
+        eprintln!("No running or waiting transfer #{}", id);
+    }
+    Ok(())
+}
+
+// Handles the /queue command for showing and changing how many transfers run at once
+fn process_queue(swarm: &mut libp2p::Swarm<ChatBehaviour>, args: &[String], state: &mut ChatState) {
+    let max = match args.get(2).map(|n| n.parse::<usize>()) {
+        None if args.len() == 1 => {
+            let (downloads, max_downloads) = state.downloads.slots();
+            let (uploads, max_uploads) = state.uploads.slots(&mut state.transfers);
+            println!("Downloads: {} of {} slots in use", downloads, max_downloads);
+            println!("Uploads: {} of {} slots in use", uploads, max_uploads);
+            for line in state.downloads.waiting() {
+                println!("  {}", line);
+            }
+            return;
+        }
+        Some(Ok(max)) if max > 0 => max,
+        _ => {
+            println!("Usage: /queue [downloads|uploads <slots>]");
+            return;
+        }
+    };
+    match args[1].as_str() {
+        "downloads" => state.downloads.set_max_active(
+            &mut swarm.behaviour_mut().request_response,
+            &mut state.transfers,
+            max,
+        ),
+        "uploads" => state.uploads.set_max_active(max),
+        _ => {
+            println!("Usage: /queue [downloads|uploads <slots>]");
+            return;
+        }
+    }
+    println!("Up to {} {} will run at once", max, args[1]);
+}
+
+// Handles the /transfers command for showing uploads and downloads by status
+fn process_transfers(transfers: &Transfers) {
+    let mut any = false;
+    for label in [
+        "Active",
+        "Queued",
+        "Paused",
+        "Finished",
+        "Skipped",
+        "Cancelled",
+        "Failed",
+    ] {
+        let group = transfers
+            .iter()
+            .filter(|t| t.status.label() == label)
+            .collect::<Vec<_>>();
+        if group.is_empty() {
+            continue;
+        }
+        any = true;
+        println!("{}:", label);
+        for transfer in group {
+            println!("  {}", transfer);
+        }
+    }
+    if !any {
+        println!("No transfers yet");
+    }
+}
+
+// Handles the /ls command for listing a peer's shared files
+fn process_ls(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    name: &str,
+    nicknames: &NicknameCache,
+) -> Result<(), Box<dyn Error>> {
+    let other_peer_id = match nicknames.resolve(name) {
+        Ok(peer_id) => peer_id,
+        Err(err) => {
+            eprintln!("Peer '{}' does not exist. Err: {}", name, err);
+            return Ok(());
+        }
+    };
+
+    swarm
+        .behaviour_mut()
+        .file_list
+        .send_req(other_peer_id, None)?;
+    Ok(())
+}
+
+// Handles the /join command for subscribing to a room
+fn process_join(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    room: &str,
+    rooms: &mut Rooms,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = Rooms::validate(room) {
+        eprintln!("{}", e);
+        return Ok(());
+    }
+
+    if !rooms.is_joined(room) {
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&Rooms::topic(room))?;
+    }
+    rooms.join(room);
+    println!("Now chatting in #{}", room);
+    Ok(())
+}
+
+// Handles the /leave command for unsubscribing from a room
+fn process_leave(
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    room: &str,
+    rooms: &mut Rooms,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = rooms.leave(room) {
+        eprintln!("{}", e);
+        return Ok(());
+    }
+
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .unsubscribe(&Rooms::topic(room))?;
+    println!("Left #{}. Now chatting in #{}", room, rooms.active());
+    Ok(())
+}
+
+// Handles the /rooms command for listing joined and advertised rooms
+fn process_rooms(rooms: &Rooms) {
+    println!("Your rooms:");
+    for room in rooms.joined() {
+        let marker = if room == rooms.active() {
+            " (active)"
+        } else {
+            ""
+        };
+        println!("  #{}{}", room, marker);
+    }
+
+    let advertised = rooms.advertised();
+    if advertised.is_empty() {
+        println!("No rooms advertised by peers");
+        return;
+    }
+    println!("Rooms advertised by peers:");
+    for (room, peers) in advertised {
+        println!("  #{} ({} peers)", room, peers);
+    }
+}
+
+// Handles the /who command for listing the known peers whose nickname starts with a prefix
+fn process_who(prefix: &str, nicknames: &NicknameCache) {
+    let peers = nicknames.starting_with(prefix);
+    if peers.is_empty() {
+        println!("No nicknames match '{}'", prefix);
+    }
+    for (nickname, peer_id) in peers {
+        println!("{} ({})", nickname, peer_id);
+    }
+}
+
+// Handles the /regenkey command for replacing the stored identity key
+fn process_regenkey(path: &Path) -> Result<(), Box<dyn Error>> {
+    let keypair = identity::generate(path)?;
+    println!(
+        "Generated a new identity at {:?}. Restart to use peer id: {}",
+        path,
+        keypair.public().to_peer_id()
+    );
+    println!("New key fingerprint: {}", identity::fingerprint(&keypair));
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Favours speed, most of the gain on text comes from the first levels
+const ZSTD_LEVEL: i32 = 3;
+
+// Formats that are already compressed and would not shrink any further
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "mkv",
+    "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip",
+    "zst",
+];
+
+// Encodings a chunk can be sent with, listed in requests so the sender only uses what we accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression '{}', expected zstd or off", s)),
+        }
+    }
+}
+
+// Parses a compression setting, where "off" accepts no encodings at all
+pub fn parse_setting(s: &str) -> Result<Vec<Compression>, String> {
+    match s {
+        "off" => Ok(Vec::new()),
+        _ => Ok(vec![s.parse()?]),
+    }
+}
+
+// Formats encodings the way parse_setting reads them
+pub fn format_setting(accepted: &[Compression]) -> String {
+    match accepted.first() {
+        Some(compression) => compression.to_string(),
+        None => "off".to_string(),
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// Whether the file name says its contents are already compressed
+pub fn is_compressed(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str()))
+}
+
+// Compresses data with the first accepted encoding, unless that does not make it smaller
+pub fn compress(data: &[u8], accepted: &[Compression]) -> Option<(Compression, Vec<u8>)> {
+    match accepted.first()? {
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+            .ok()
+            .filter(|compressed| compressed.len() < data.len())
+            .map(|compressed| (Compression::Zstd, compressed)),
+    }
+}
+
+// Decompresses data, refusing to produce more than max_len bytes
+pub fn decompress(
+    data: &[u8],
+    encoding: Compression,
+    max_len: usize,
+) -> Result<Vec<u8>, std::io::Error> {
+    match encoding {
+        Compression::Zstd => zstd::bulk::decompress(data, max_len),
+    }
+}
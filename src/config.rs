@@ -0,0 +1,701 @@
+use crate::compression::{self, Compression};
+use crate::identity;
+use crate::limits::{self, LimitOverrides, Limits};
+use crate::sendfile::{self, Collision};
+
+use libp2p::gossipsub;
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+const APP_DIR: &str = "swapbytes";
+const CONFIG_FILE: &str = "config.toml";
+
+// Addresses we listen on unless others are given, any interface on a random port
+const DEFAULT_LISTEN: &[&str] = &["/ip4/0.0.0.0/udp/0/quic-v1", "/ip4/0.0.0.0/tcp/0"];
+// Connections with no traffic are closed after this long
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_LOG_LEVEL: &str = "error";
+
+// The user's home directory, or the working directory if there is none
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+// A base directory from the XDG spec, which says relative values are to be ignored
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match std::env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => home_dir().join(fallback),
+    }
+}
+
+// Where shared, received and stored files live unless configured otherwise
+pub fn data_home() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share").join(APP_DIR)
+}
+
+// Where the config file is read from unless another one is given
+pub fn config_path() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+        .join(APP_DIR)
+        .join(CONFIG_FILE)
+}
+
+// One layer of settings, from the config file, the environment or the command line.
+// Anything left out falls through to the layer below, and in the end to the defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub nickname: Option<String>,
+    pub identity: Option<PathBuf>,
+    // Holds the identity, serving policy and bandwidth limits
+    pub data_dir: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub output: Option<OutputMode>,
+    pub network: NetworkConfig,
+    pub gossipsub: GossipsubConfig,
+    pub transfers: TransferConfig,
+    pub limits: LimitsConfig,
+    pub directories: DirectoryConfig,
+}
+
+impl Config {
+    // Reads the config file at path. The default file may be missing, one given explicitly may not.
+    pub fn load(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), Box<dyn Error>> {
+        let default_path = config_path();
+        let path = path.unwrap_or(&default_path);
+        if path == default_path && !path.exists() {
+            return Ok((Config::default(), None));
+        }
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {:?}: {}", path, e))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| format!("Config file {:?} is invalid: {}", path, e))?;
+
+        // Relative paths in the file are taken from where the file is, not where we were started
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |path: &mut PathBuf| *path = expand(base, path);
+        config.identity.iter_mut().for_each(resolve);
+        config.data_dir.iter_mut().for_each(resolve);
+        config.directories.make_absolute(base);
+        Ok((config, Some(path.to_path_buf())))
+    }
+
+    // Layers other on top, so its settings win
+    pub fn merge(self, other: Config) -> Self {
+        Config {
+            nickname: other.nickname.or(self.nickname),
+            identity: other.identity.or(self.identity),
+            data_dir: other.data_dir.or(self.data_dir),
+            log_level: other.log_level.or(self.log_level),
+            output: other.output.or(self.output),
+            network: self.network.merge(other.network),
+            gossipsub: self.gossipsub.merge(other.gossipsub),
+            transfers: self.transfers.merge(other.transfers),
+            limits: self.limits.merge(other.limits),
+            directories: self.directories.merge(other.directories),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub listen: Option<Vec<String>>,
+    // Peers to dial on startup, each ending in /p2p/<peer id>
+    pub bootstrap: Option<Vec<String>>,
+    pub idle_timeout_secs: Option<u64>,
+    pub protocols: ProtocolConfig,
+}
+
+impl NetworkConfig {
+    fn merge(self, other: NetworkConfig) -> Self {
+        NetworkConfig {
+            listen: other.listen.or(self.listen),
+            bootstrap: other.bootstrap.or(self.bootstrap),
+            idle_timeout_secs: other.idle_timeout_secs.or(self.idle_timeout_secs),
+            protocols: self.protocols.merge(other.protocols),
+        }
+    }
+}
+
+// Names of our request-response protocols, peers only talk if theirs are the same
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    pub file_exchange: Option<String>,
+    pub direct_message: Option<String>,
+    pub file_list: Option<String>,
+    pub file_offer: Option<String>,
+}
+
+impl ProtocolConfig {
+    fn merge(self, other: ProtocolConfig) -> Self {
+        ProtocolConfig {
+            file_exchange: other.file_exchange.or(self.file_exchange),
+            direct_message: other.direct_message.or(self.direct_message),
+            file_list: other.file_list.or(self.file_list),
+            file_offer: other.file_offer.or(self.file_offer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipsubConfig {
+    pub heartbeat_interval_ms: Option<u64>,
+    // Target number of peers in the mesh for each room, and the bounds it is kept within
+    pub mesh_n: Option<usize>,
+    pub mesh_n_low: Option<usize>,
+    pub mesh_n_high: Option<usize>,
+    pub max_transmit_size: Option<usize>,
+}
+
+impl GossipsubConfig {
+    fn merge(self, other: GossipsubConfig) -> Self {
+        GossipsubConfig {
+            heartbeat_interval_ms: other.heartbeat_interval_ms.or(self.heartbeat_interval_ms),
+            mesh_n: other.mesh_n.or(self.mesh_n),
+            mesh_n_low: other.mesh_n_low.or(self.mesh_n_low),
+            mesh_n_high: other.mesh_n_high.or(self.mesh_n_high),
+            max_transmit_size: other.max_transmit_size.or(self.max_transmit_size),
+        }
+    }
+
+    // Builds the gossipsub config, starting from the libp2p defaults
+    fn build(&self) -> Result<gossipsub::Config, String> {
+        let mut builder = gossipsub::ConfigBuilder::default();
+        if let Some(ms) = self.heartbeat_interval_ms {
+            if ms == 0 {
+                return Err("heartbeat_interval_ms must be at least 1".to_string());
+            }
+            builder.heartbeat_interval(Duration::from_millis(ms));
+        }
+        if let Some(n) = self.mesh_n {
+            builder.mesh_n(n);
+        }
+        if let Some(n) = self.mesh_n_low {
+            builder.mesh_n_low(n);
+        }
+        if let Some(n) = self.mesh_n_high {
+            builder.mesh_n_high(n);
+        }
+        if let Some(size) = self.max_transmit_size {
+            builder.max_transmit_size(size);
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    pub chunk_size: Option<u64>,
+    pub on_conflict: Option<String>,
+    pub max_downloads: Option<usize>,
+    pub max_uploads: Option<usize>,
+    pub compression: Option<String>,
+}
+
+impl TransferConfig {
+    fn merge(self, other: TransferConfig) -> Self {
+        TransferConfig {
+            chunk_size: other.chunk_size.or(self.chunk_size),
+            on_conflict: other.on_conflict.or(self.on_conflict),
+            max_downloads: other.max_downloads.or(self.max_downloads),
+            max_uploads: other.max_uploads.or(self.max_uploads),
+            compression: other.compression.or(self.compression),
+        }
+    }
+}
+
+// Bandwidth limits on file traffic, rates such as "500KB" or "off".
+// Each one set here replaces the one /limit saved in the data directory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Over all peers together
+    pub upload: Option<String>,
+    pub download: Option<String>,
+    // With each peer separately, uploads and downloads together
+    pub per_peer: Option<String>,
+    // Replaces per_peer for particular peers, by peer id or nickname
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub peers: BTreeMap<String, String>,
+}
+
+impl LimitsConfig {
+    // The limits in effect, as they would be written in the file
+    pub fn from_limits(limits: &Limits) -> Self {
+        LimitsConfig {
+            upload: Some(format_limit(limits.upload)),
+            download: Some(format_limit(limits.download)),
+            per_peer: Some(format_limit(limits.per_peer)),
+            peers: limits
+                .peers
+                .iter()
+                .map(|(peer, rate)| (peer.clone(), format_limit(Some(*rate))))
+                .collect(),
+        }
+    }
+
+    fn merge(mut self, other: LimitsConfig) -> Self {
+        self.upload = other.upload.or(self.upload);
+        self.download = other.download.or(self.download);
+        self.per_peer = other.per_peer.or(self.per_peer);
+        self.peers.extend(other.peers);
+        self
+    }
+
+    // Checks every rate against the chunk size in use
+    fn parse(self, errors: &mut Vec<String>, chunk_size: u64) -> LimitOverrides {
+        let mut rate = |name: &str, value: String| {
+            check(errors, name, limits::parse_limit(&value, chunk_size))
+        };
+        LimitOverrides {
+            upload: self.upload.and_then(|value| rate("limits.upload", value)),
+            download: self
+                .download
+                .and_then(|value| rate("limits.download", value)),
+            per_peer: self
+                .per_peer
+                .and_then(|value| rate("limits.per_peer", value)),
+            peers: self
+                .peers
+                .into_iter()
+                .filter_map(|(peer, value)| Some((peer, rate("limits.peers", value)?)))
+                .collect(),
+        }
+    }
+}
+
+// A rate in bytes per second as parse_rate reads it back
+fn format_limit(rate: Option<u64>) -> String {
+    rate.map_or_else(|| "off".to_string(), |rate| rate.to_string())
+}
+
+// Directories as set in the config file or on the command line
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectoryConfig {
+    // The ByteBeam, shared with peers
+    pub share: Option<PathBuf>,
+    // The ByteStream, where received files are saved
+    pub downloads: Option<PathBuf>,
+    // The content-addressed store behind the ByteStream
+    pub store: Option<PathBuf>,
+    // Extra directories to share, by the alias they appear under in the ByteBeam
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub shares: BTreeMap<String, PathBuf>,
+}
+
+impl DirectoryConfig {
+    fn make_absolute(&mut self, base: &Path) {
+        let resolve = |path: &mut PathBuf| *path = expand(base, path);
+        self.share.iter_mut().for_each(resolve);
+        self.downloads.iter_mut().for_each(resolve);
+        self.store.iter_mut().for_each(resolve);
+        self.shares.values_mut().for_each(resolve);
+    }
+
+    // Layers other on top, so its settings win and its shares are added
+    pub fn merge(mut self, other: DirectoryConfig) -> Self {
+        self.share = other.share.or(self.share);
+        self.downloads = other.downloads.or(self.downloads);
+        self.store = other.store.or(self.store);
+        self.shares.extend(other.shares);
+        self
+    }
+}
+
+// Expands a leading ~ to the home directory and makes a relative path relative to base
+fn expand(base: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => home_dir().join(rest),
+        Err(_) => base.join(path),
+    }
+}
+
+// Makes a path from the command line independent of where we were started, so the settings
+// /config shows mean the same from anywhere
+fn absolute(path: PathBuf) -> PathBuf {
+    std::path::absolute(&path).unwrap_or(path)
+}
+
+// Parses an extra share given on the command line as alias=path
+pub fn parse_share(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((alias, path)) if !path.is_empty() => Ok((alias.to_string(), PathBuf::from(path))),
+        _ => Err(format!("expected alias=path, got '{}'", s)),
+    }
+}
+
+// Parses a bootstrap peer, which has to end in /p2p/<peer id> so we know who we are dialling
+pub fn parse_bootstrap(s: &str) -> Result<Multiaddr, String> {
+    let addr = s
+        .parse::<Multiaddr>()
+        .map_err(|e| format!("invalid address '{}': {}", s, e))?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(_)) => Ok(addr),
+        _ => Err(format!("address '{}' does not end in /p2p/<peer id>", s)),
+    }
+}
+
+fn parse_listen(s: &str) -> Result<Multiaddr, String> {
+    s.parse()
+        .map_err(|e| format!("invalid address '{}': {}", s, e))
+}
+
+fn parse_protocol(name: Option<String>, default: &'static str) -> Result<StreamProtocol, String> {
+    match name {
+        None => Ok(StreamProtocol::new(default)),
+        Some(name) => StreamProtocol::try_from_owned(name.clone())
+            .map_err(|_| format!("'{}' must start with /", name)),
+    }
+}
+
+// How the app talks to whoever is at the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    // Prompts for anything missing and prints hints on how to use the chat
+    #[default]
+    Interactive,
+    // Never prompts and prints only what happens, for scripts and services
+    Plain,
+}
+
+// Names of the request-response protocols in use
+#[derive(Debug, Clone)]
+pub struct Protocols {
+    pub file_exchange: StreamProtocol,
+    pub direct_message: StreamProtocol,
+    pub file_list: StreamProtocol,
+    pub file_offer: StreamProtocol,
+}
+
+// The settings in effect, with every layer applied and the defaults filled in
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // The config file that was read, if any
+    pub config_file: Option<PathBuf>,
+    pub nickname: String,
+    pub identity: PathBuf,
+    pub data_dir: PathBuf,
+    pub log_level: String,
+    pub output: OutputMode,
+    pub listen: Vec<Multiaddr>,
+    pub bootstrap: Vec<Multiaddr>,
+    pub idle_timeout: Duration,
+    pub protocols: Protocols,
+    pub gossipsub: gossipsub::Config,
+    pub chunk_size: u64,
+    pub collision: Collision,
+    pub max_downloads: usize,
+    pub max_uploads: usize,
+    pub compression: Vec<Compression>,
+    pub limits: LimitOverrides,
+    pub directories: Directories,
+}
+
+// Records a problem with a setting, so they can all be reported at once
+fn check<T>(errors: &mut Vec<String>, name: &str, result: Result<T, String>) -> Option<T> {
+    result
+        .map_err(|e| errors.push(format!("{}: {}", name, e)))
+        .ok()
+}
+
+impl Settings {
+    // Checks the merged layers and fills in the defaults, reporting every invalid setting
+    pub fn new(config: Config, config_file: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let mut errors = Vec::new();
+
+        let nickname = match config.nickname {
+            None => {
+                errors.push("nickname: not set, use --nickname or the config file".to_string());
+                String::new()
+            }
+            Some(nickname) if nickname.trim().is_empty() => {
+                errors.push("nickname: must not be empty".to_string());
+                nickname
+            }
+            Some(nickname) => nickname,
+        };
+        let data_dir = absolute(config.data_dir.unwrap_or_else(data_home));
+        let log_level = config.log_level.unwrap_or_else(|| {
+            std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_LEVEL.to_string())
+        });
+        check(
+            &mut errors,
+            "log_level",
+            EnvFilter::try_new(&log_level).map_err(|e| e.to_string()),
+        );
+
+        let network = config.network;
+        let listen = match network.listen {
+            Some(listen) => listen,
+            None => DEFAULT_LISTEN.iter().map(|addr| addr.to_string()).collect(),
+        };
+        let listen = listen
+            .iter()
+            .filter_map(|addr| check(&mut errors, "network.listen", parse_listen(addr)))
+            .collect();
+        let bootstrap = network
+            .bootstrap
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|addr| check(&mut errors, "network.bootstrap", parse_bootstrap(addr)))
+            .collect();
+        let idle_timeout = network
+            .idle_timeout_secs
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+        if idle_timeout == 0 {
+            errors.push("network.idle_timeout_secs: must be at least 1".to_string());
+        }
+
+        let names = network.protocols;
+        let mut protocol = |name: &str, value: Option<String>, default: &'static str| {
+            check(&mut errors, name, parse_protocol(value, default))
+                .unwrap_or(StreamProtocol::new(default))
+        };
+        let protocols = Protocols {
+            file_exchange: protocol(
+                "network.protocols.file_exchange",
+                names.file_exchange,
+                "/file-exchange/2",
+            ),
+            direct_message: protocol(
+                "network.protocols.direct_message",
+                names.direct_message,
+                "/direct-message/1",
+            ),
+            file_list: protocol(
+                "network.protocols.file_list",
+                names.file_list,
+                "/file-list/1",
+            ),
+            file_offer: protocol(
+                "network.protocols.file_offer",
+                names.file_offer,
+                "/file-offer/1",
+            ),
+        };
+        let distinct = [
+            &protocols.file_exchange,
+            &protocols.direct_message,
+            &protocols.file_list,
+            &protocols.file_offer,
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>();
+        if distinct.len() < 4 {
+            errors.push("network.protocols: every protocol needs its own name".to_string());
+        }
+
+        let gossipsub =
+            check(&mut errors, "gossipsub", config.gossipsub.build()).unwrap_or_default();
+
+        let transfers = config.transfers;
+        let chunk_size = transfers.chunk_size.unwrap_or(sendfile::DEFAULT_CHUNK_SIZE);
+        if !(sendfile::MIN_CHUNK_SIZE..=sendfile::MAX_CHUNK_SIZE).contains(&chunk_size) {
+            errors.push(format!(
+                "transfers.chunk_size: must be between {} and {} bytes",
+                sendfile::MIN_CHUNK_SIZE,
+                sendfile::MAX_CHUNK_SIZE
+            ));
+        }
+        let collision = match transfers.on_conflict {
+            Some(strategy) => check(&mut errors, "transfers.on_conflict", strategy.parse()),
+            None => None,
+        }
+        .unwrap_or(Collision::Rename);
+        let max_downloads = transfers
+            .max_downloads
+            .unwrap_or(sendfile::DEFAULT_MAX_DOWNLOADS);
+        let max_uploads = transfers
+            .max_uploads
+            .unwrap_or(sendfile::DEFAULT_MAX_UPLOADS);
+        if max_downloads == 0 {
+            errors.push("transfers.max_downloads: must be at least 1".to_string());
+        }
+        if max_uploads == 0 {
+            errors.push("transfers.max_uploads: must be at least 1".to_string());
+        }
+        let compression = match transfers.compression {
+            Some(setting) => check(
+                &mut errors,
+                "transfers.compression",
+                compression::parse_setting(&setting),
+            ),
+            None => None,
+        }
+        .unwrap_or_else(|| vec![Compression::Zstd]);
+
+        let limits = config.limits.parse(&mut errors, chunk_size);
+
+        let directories = check(
+            &mut errors,
+            "directories",
+            Directories::new(config.directories),
+        );
+
+        match directories {
+            Some(directories) if errors.is_empty() => Ok(Settings {
+                config_file,
+                nickname,
+                identity: absolute(
+                    config
+                        .identity
+                        .unwrap_or_else(|| identity::key_path(&data_dir)),
+                ),
+                data_dir,
+                log_level,
+                output: config.output.unwrap_or_default(),
+                listen,
+                bootstrap,
+                idle_timeout: Duration::from_secs(idle_timeout),
+                protocols,
+                gossipsub,
+                chunk_size,
+                collision,
+                max_downloads,
+                max_uploads,
+                compression,
+                limits,
+                directories,
+            }),
+            _ => Err(format!("Invalid settings:\n  {}", errors.join("\n  ")).into()),
+        }
+    }
+
+    // The settings as a config layer, which written to a file gives the same settings again
+    pub fn to_config(&self) -> Config {
+        let strings = |addrs: &[Multiaddr]| addrs.iter().map(|a| a.to_string()).collect();
+        Config {
+            nickname: Some(self.nickname.clone()),
+            identity: Some(self.identity.clone()),
+            data_dir: Some(self.data_dir.clone()),
+            log_level: Some(self.log_level.clone()),
+            output: Some(self.output),
+            network: NetworkConfig {
+                listen: Some(strings(&self.listen)),
+                bootstrap: Some(strings(&self.bootstrap)),
+                idle_timeout_secs: Some(self.idle_timeout.as_secs()),
+                protocols: ProtocolConfig {
+                    file_exchange: Some(self.protocols.file_exchange.to_string()),
+                    direct_message: Some(self.protocols.direct_message.to_string()),
+                    file_list: Some(self.protocols.file_list.to_string()),
+                    file_offer: Some(self.protocols.file_offer.to_string()),
+                },
+            },
+            gossipsub: GossipsubConfig {
+                heartbeat_interval_ms: Some(self.gossipsub.heartbeat_interval().as_millis() as u64),
+                mesh_n: Some(self.gossipsub.mesh_n()),
+                mesh_n_low: Some(self.gossipsub.mesh_n_low()),
+                mesh_n_high: Some(self.gossipsub.mesh_n_high()),
+                max_transmit_size: Some(self.gossipsub.max_transmit_size()),
+            },
+            transfers: TransferConfig {
+                chunk_size: Some(self.chunk_size),
+                on_conflict: Some(self.collision.to_string()),
+                max_downloads: Some(self.max_downloads),
+                max_uploads: Some(self.max_uploads),
+                compression: Some(compression::format_setting(&self.compression)),
+            },
+            limits: LimitsConfig {
+                upload: self.limits.upload.map(format_limit),
+                download: self.limits.download.map(format_limit),
+                per_peer: self.limits.per_peer.map(format_limit),
+                peers: self
+                    .limits
+                    .peers
+                    .iter()
+                    .map(|(peer, rate)| (peer.clone(), format_limit(*rate)))
+                    .collect(),
+            },
+            directories: DirectoryConfig {
+                share: Some(self.directories.share.clone()),
+                downloads: Some(self.directories.downloads.clone()),
+                store: Some(self.directories.store.clone()),
+                shares: self.directories.shares.clone(),
+            },
+        }
+    }
+}
+
+// The directories in use, with the defaults filled in
+#[derive(Debug, Clone)]
+pub struct Directories {
+    pub share: PathBuf,
+    pub downloads: PathBuf,
+    pub store: PathBuf,
+    pub shares: BTreeMap<String, PathBuf>,
+}
+
+impl Directories {
+    pub fn new(config: DirectoryConfig) -> Result<Self, String> {
+        let data_home = data_home();
+        for (alias, path) in &config.shares {
+            // An alias is the first component of the names peers see, so it has to be a plain name
+            let plain = matches!(
+                Path::new(alias).components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            );
+            if !plain || alias.contains('\\') {
+                return Err(format!("share alias '{}' must be a plain name", alias));
+            }
+            if !path.is_dir() {
+                return Err(format!("share {} at {:?} is not a directory", alias, path));
+            }
+        }
+        let directories = Directories {
+            share: absolute(config.share.unwrap_or_else(|| data_home.join("bytebeam"))),
+            downloads: absolute(
+                config
+                    .downloads
+                    .unwrap_or_else(|| data_home.join("bytestream")),
+            ),
+            store: absolute(config.store.unwrap_or_else(|| data_home.join("store"))),
+            shares: config
+                .shares
+                .into_iter()
+                .map(|(alias, path)| (alias, absolute(path)))
+                .collect(),
+        };
+
+        // Everything received would be shared again, and every save would trigger a rescan
+        let roots = std::iter::once(&directories.share).chain(directories.shares.values());
+        for root in roots {
+            for (name, dir) in [
+                ("downloads", &directories.downloads),
+                ("store", &directories.store),
+            ] {
+                if real(dir).starts_with(real(root)) {
+                    return Err(format!(
+                        "{} {:?} must not be inside the shared directory {:?}",
+                        name, dir, root
+                    ));
+                }
+            }
+        }
+        Ok(directories)
+    }
+}
+
+// Follows symlinks in a path that exists, so two spellings of a directory compare equal
+fn real(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
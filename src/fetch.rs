@@ -0,0 +1,663 @@
+use crate::catalogue;
+use crate::compression::Compression;
+use crate::search;
+use crate::sendfile::{
+    self, Collision, Direction, FileError, FileRequest, FileResponse, SendFileBehaviour, Transfer,
+    TransferStatus, Transfers,
+};
+use crate::store::BlobStore;
+
+use libp2p::kad::store::MemoryStore;
+use libp2p::kad::{self, QueryId};
+use libp2p::request_response::OutboundRequestId;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+// A chunk taking this many times longer than the typical chunk marks its peer as slow
+const SLOW_FACTOR: u32 = 4;
+// ...but only once it has taken at least this long
+const MIN_SLOW_TIMEOUT: Duration = Duration::from_secs(10);
+// Chunks to time before judging which peers are slow
+const MIN_SAMPLES: usize = 3;
+// Largest file fetched by hash, the .part file is given its full size up front
+const MAX_FETCH_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Missing,
+    InFlight,
+    Done,
+}
+
+// A file fetched by content hash, with different chunks coming from different providers
+struct Fetch {
+    hash: String,
+    // Set once the chunk digests arrive, along with the name the provider uses
+    transfer_id: Option<u64>,
+    filename: String,
+    total_size: u64,
+    digests: Vec<String>,
+    chunks: Vec<Chunk>,
+    received: u64,
+    // Providers still in use, and those dropped for being slow or sending bad data
+    peers: Vec<PeerId>,
+    dropped: HashSet<PeerId>,
+    // Provider the digests came from, and the peers dropped for chunks that did not match them
+    digest_source: Option<PeerId>,
+    mismatched: HashSet<PeerId>,
+    // Providers whose upload slots were full, and when to ask them again
+    busy: HashMap<PeerId, Instant>,
+    // Chunk index of each request, None for the digest request, with the peer and send time
+    requests: HashMap<OutboundRequestId, (Option<usize>, PeerId, Instant)>,
+    // How long the chunks received so far took
+    durations: Vec<Duration>,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: Option<File>,
+}
+
+impl Fetch {
+    fn new(hash: &str) -> Self {
+        Fetch {
+            hash: hash.to_string(),
+            transfer_id: None,
+            filename: String::new(),
+            total_size: 0,
+            digests: Vec::new(),
+            chunks: Vec::new(),
+            received: 0,
+            peers: Vec::new(),
+            dropped: HashSet::new(),
+            digest_source: None,
+            mismatched: HashSet::new(),
+            busy: HashMap::new(),
+            requests: HashMap::new(),
+            durations: Vec::new(),
+            path: PathBuf::new(),
+            part_path: PathBuf::new(),
+            file: None,
+        }
+    }
+
+    // Short form of the hash for messages
+    fn name(&self) -> &str {
+        if self.filename.is_empty() {
+            &self.hash[..16]
+        } else {
+            &self.filename
+        }
+    }
+
+    // Creates the .part file at its full size once the provider has named the file
+    async fn open(&mut self, dir: &Path) -> Result<(), std::io::Error> {
+        self.path =
+            sendfile::download_path(dir, &self.filename).unwrap_or_else(|| dir.join(&self.hash));
+        let mut part_path = self.path.clone().into_os_string();
+        part_path.push(".part");
+        self.part_path = PathBuf::from(part_path);
+        if let Some(parent) = self.part_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = File::create(&self.part_path).await?;
+        let sized = file.set_len(self.total_size).await;
+        self.file = Some(file);
+        sized
+    }
+
+    async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        if let Some(file) = self.file.as_mut() {
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.file.is_some() && self.chunks.iter().all(|c| *c == Chunk::Done)
+    }
+
+    // Gives every idle peer something to do: the digests first, then a missing chunk each
+    fn schedule(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        chunk_size: u64,
+        compression: &[Compression],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Instant::now();
+        self.busy.retain(|_, retry_at| *retry_at > now);
+        let ready = self
+            .peers
+            .iter()
+            .filter(|peer_id| !self.busy.contains_key(peer_id))
+            .copied()
+            .collect::<Vec<PeerId>>();
+
+        if self.file.is_none() {
+            let asked = self.requests.values().any(|(chunk, _, _)| chunk.is_none());
+            if let (false, Some(peer_id)) = (asked, ready.first().copied()) {
+                self.send(behaviour, peer_id, None, chunk_size, compression)?;
+            }
+            return Ok(());
+        }
+
+        for peer_id in ready {
+            if self.requests.values().any(|(_, peer, _)| *peer == peer_id) {
+                continue;
+            }
+            let Some(index) = self.chunks.iter().position(|c| *c == Chunk::Missing) else {
+                break;
+            };
+            self.chunks[index] = Chunk::InFlight;
+            self.send(behaviour, peer_id, Some(index), chunk_size, compression)?;
+        }
+        Ok(())
+    }
+
+    fn send(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        peer_id: PeerId,
+        chunk: Option<usize>,
+        chunk_size: u64,
+        compression: &[Compression],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_id = behaviour.send_req(
+            peer_id,
+            FileRequest {
+                filename: String::new(),
+                offset: chunk.map_or(0, |index| index as u64 * chunk_size),
+                length: chunk_size,
+                hash: Some(self.hash.clone()),
+                compression: compression.to_vec(),
+                digests: chunk.is_none(),
+                cancel: false,
+            },
+        )?;
+        self.requests
+            .insert(request_id, (chunk, peer_id, Instant::now()));
+        Ok(())
+    }
+
+    // Asks a peer with no free upload slot again later, handing its chunk back meanwhile
+    fn wait_for(&mut self, peer_id: PeerId, chunk: Option<usize>) {
+        println!(
+            "Peer {} is busy, fetching {} from it again in {}s",
+            peer_id,
+            self.name(),
+            sendfile::BUSY_RETRY.as_secs()
+        );
+        if let Some(index) = chunk {
+            self.chunks[index] = Chunk::Missing;
+        }
+        self.busy
+            .insert(peer_id, Instant::now() + sendfile::BUSY_RETRY);
+    }
+
+    // Stops using a peer, handing its chunks back to the others
+    fn drop_peer(&mut self, peer_id: PeerId, reason: &str) {
+        println!(
+            "Dropped peer {} from fetch of {}: {}",
+            peer_id,
+            self.name(),
+            reason
+        );
+        self.peers.retain(|p| *p != peer_id);
+        self.dropped.insert(peer_id);
+
+        let chunks = &mut self.chunks;
+        self.requests.retain(|_, (chunk, peer, _)| {
+            if *peer != peer_id {
+                return true;
+            }
+            if let Some(index) = chunk {
+                chunks[*index] = Chunk::Missing;
+            }
+            false
+        });
+    }
+
+    // Drops a peer whose chunk did not match its digest. Once chunks from two peers fail, the
+    // digests are more likely wrong than both peers, so their source is dropped instead, the
+    // two peers are taken back and the digests are asked for again.
+    fn mismatch(&mut self, peer_id: PeerId) {
+        self.drop_peer(peer_id, "sent a chunk that does not match its digest");
+        let Some(source) = self.digest_source else {
+            return;
+        };
+        if peer_id != source {
+            self.mismatched.insert(peer_id);
+            if self.mismatched.len() < 2 {
+                return;
+            }
+            self.drop_peer(source, "sent chunk digests that other peers do not match");
+        }
+        for peer_id in self.mismatched.drain() {
+            self.dropped.remove(&peer_id);
+            self.peers.push(peer_id);
+        }
+        self.restart();
+    }
+
+    // Throws away the digests and every chunk checked against them
+    fn restart(&mut self) {
+        println!("Restarting fetch of {} with new chunk digests", self.name());
+        self.digest_source = None;
+        self.digests.clear();
+        self.chunks.clear();
+        self.received = 0;
+        // Answers to the requests in flight would be checked against the old digests
+        self.requests.clear();
+        drop(self.file.take());
+        let _ = std::fs::remove_file(&self.part_path);
+    }
+
+    // Drops peers whose chunk is taking far longer than usual, unless they are the last one
+    fn drop_slow_peers(&mut self) {
+        if self.durations.len() < MIN_SAMPLES {
+            return;
+        }
+        let mut durations = self.durations.clone();
+        durations.sort();
+        let timeout = (durations[durations.len() / 2] * SLOW_FACTOR).max(MIN_SLOW_TIMEOUT);
+
+        let slow = self
+            .requests
+            .values()
+            .filter(|(chunk, _, sent)| chunk.is_some() && sent.elapsed() > timeout)
+            .map(|(_, peer_id, _)| *peer_id)
+            .collect::<Vec<PeerId>>();
+        for peer_id in slow {
+            if self.peers.len() > 1 {
+                self.drop_peer(peer_id, "too slow");
+            }
+        }
+    }
+}
+
+// Multi-source downloads by content hash, fed by Kademlia provider lookups
+pub struct Fetches {
+    // Where fetched files are saved
+    dir: PathBuf,
+    chunk_size: u64,
+    compression: Vec<Compression>,
+    queries: HashMap<QueryId, String>,
+    fetches: Vec<Fetch>,
+}
+
+impl Fetches {
+    pub fn new(dir: PathBuf, chunk_size: u64, compression: &[Compression]) -> Self {
+        Fetches {
+            dir,
+            compression: compression.to_vec(),
+            chunk_size: chunk_size.clamp(sendfile::MIN_CHUNK_SIZE, sendfile::MAX_CHUNK_SIZE),
+            queries: HashMap::new(),
+            fetches: Vec::new(),
+        }
+    }
+
+    // Looks up the providers of a hash, chunks are requested as they are found
+    pub fn start(
+        &mut self,
+        kademlia: &mut kad::Behaviour<MemoryStore>,
+        hash: &str,
+    ) -> Result<(), String> {
+        if !search::is_hash(hash) {
+            return Err(format!("'{}' is not a SHA-256 hash", hash));
+        }
+        let hash = hash.to_lowercase();
+        if self.fetches.iter().any(|f| f.hash == hash) {
+            return Err(format!("Already fetching {}", hash));
+        }
+
+        let query_id = kademlia.get_providers(search::hash_key(&hash));
+        self.queries.insert(query_id, hash.clone());
+        self.fetches.push(Fetch::new(&hash));
+        Ok(())
+    }
+
+    // Adds providers found by a lookup, returning false if the lookup is not ours
+    pub fn found_providers(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        query_id: &QueryId,
+        providers: &[PeerId],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(hash) = self.queries.get(query_id) else {
+            return Ok(false);
+        };
+        let Some(fetch) = self.fetches.iter_mut().find(|f| f.hash == *hash) else {
+            return Ok(true);
+        };
+
+        for peer_id in providers {
+            if !fetch.peers.contains(peer_id) && !fetch.dropped.contains(peer_id) {
+                println!("Fetching {} from peer {}", fetch.name(), peer_id);
+                fetch.peers.push(*peer_id);
+            }
+        }
+        fetch.schedule(behaviour, self.chunk_size, &self.compression)?;
+        Ok(true)
+    }
+
+    // Forgets a finished lookup, giving up on its fetch if no usable peer provides the hash
+    pub fn finish_query(&mut self, transfers: &mut Transfers, query_id: &QueryId) -> bool {
+        let Some(hash) = self.queries.remove(query_id) else {
+            return false;
+        };
+        if let Some(index) = self
+            .fetches
+            .iter()
+            .position(|f| f.hash == hash && f.peers.is_empty())
+        {
+            let fetch = self.fetches.remove(index);
+            fail(transfers, fetch, "no peers provide it");
+        }
+        true
+    }
+
+    // When a fetch waiting on a busy peer is due to ask it again
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.fetches
+            .iter()
+            .flat_map(|f| f.busy.values())
+            .min()
+            .copied()
+    }
+
+    // Asks busy peers again for the fetches that are due
+    pub fn retry(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for fetch in &mut self.fetches {
+            fetch.schedule(behaviour, self.chunk_size, &self.compression)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_compression(&mut self, compression: &[Compression]) {
+        self.compression = compression.to_vec();
+    }
+
+    // Whether a file exchange request was sent by one of our fetches
+    pub fn owns(&self, request_id: &OutboundRequestId) -> bool {
+        self.fetches
+            .iter()
+            .any(|f| f.requests.contains_key(request_id))
+    }
+
+    // Checks and writes a chunk, then keeps every remaining peer busy
+    pub async fn handle_response(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        store: &mut BlobStore,
+        request_id: OutboundRequestId,
+        response: FileResponse,
+        collision: Collision,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(index) = self
+            .fetches
+            .iter()
+            .position(|f| f.requests.contains_key(&request_id))
+        else {
+            return Ok(());
+        };
+        let chunk_size = self.chunk_size;
+        let fetch = &mut self.fetches[index];
+        let Some((chunk, peer_id, sent)) = fetch.requests.remove(&request_id) else {
+            return Ok(());
+        };
+
+        // Disk errors fail this fetch, not the whole session
+        let written = match (chunk, response) {
+            (_, Err(FileError::Busy)) => {
+                fetch.wait_for(peer_id, chunk);
+                Ok(())
+            }
+            (_, Err(e)) => {
+                fetch.drop_peer(peer_id, &e.to_string());
+                Ok(())
+            }
+            (_, Ok(reply)) if reply.hash != fetch.hash => {
+                fetch.drop_peer(peer_id, "file changed");
+                Ok(())
+            }
+            (None, Ok(reply)) => {
+                if reply.total_size > MAX_FETCH_SIZE {
+                    fetch.drop_peer(peer_id, "file is too large");
+                    Ok(())
+                } else if reply.digests.len() as u64 != reply.total_size.div_ceil(chunk_size) {
+                    fetch.drop_peer(peer_id, "sent the wrong number of chunk digests");
+                    Ok(())
+                } else {
+                    // The provider picks the name, so one we cannot save under becomes the hash
+                    fetch.filename = match sendfile::download_path(&self.dir, &reply.filename) {
+                        Some(_) => reply.filename,
+                        None => fetch.hash.clone(),
+                    };
+                    fetch.total_size = reply.total_size;
+                    fetch.chunks = vec![Chunk::Missing; reply.digests.len()];
+                    fetch.digests = reply.digests;
+                    fetch.digest_source = Some(peer_id);
+                    let opened = fetch.open(&self.dir).await;
+                    match fetch.transfer_id {
+                        // Digests asked for again start the progress over
+                        Some(id) => transfers.progress(id, 0, fetch.total_size),
+                        None => {
+                            fetch.transfer_id = Some(transfers.add(
+                                Direction::Download,
+                                peer_id,
+                                &fetch.filename,
+                                TransferStatus::Active,
+                            ))
+                        }
+                    }
+                    opened
+                }
+            }
+            (Some(chunk), Ok(reply)) => {
+                if let Some(id) = fetch.transfer_id {
+                    transfers.wire(id, reply.data.len() as u64, reply.encoding);
+                }
+
+                // Every chunk must match its digest, so a bad peer is caught straight away
+                let offset = chunk as u64 * chunk_size;
+                let length = chunk_size.min(fetch.total_size - offset);
+                let valid = reply.decompress(chunk_size).ok().filter(|reply| {
+                    reply.offset == offset
+                        && reply.data.len() as u64 == length
+                        && catalogue::to_hex(&Sha256::digest(&reply.data)) == fetch.digests[chunk]
+                });
+                match valid {
+                    Some(reply) if fetch.file.is_some() => {
+                        let written = fetch.write(offset, &reply.data).await;
+                        fetch.chunks[chunk] = Chunk::Done;
+                        fetch.received += length;
+                        fetch.durations.push(sent.elapsed());
+                        if let Some(id) = fetch.transfer_id {
+                            transfers.progress(id, fetch.received, fetch.total_size);
+                        }
+                        written
+                    }
+                    _ => {
+                        fetch.mismatch(peer_id);
+                        Ok(())
+                    }
+                }
+            }
+        };
+        if let Err(e) = written {
+            let fetch = self.fetches.remove(index);
+            fail(
+                transfers,
+                fetch,
+                &format!("could not write the file ({})", e),
+            );
+            return Ok(());
+        }
+        fetch.drop_slow_peers();
+
+        if fetch.is_complete() {
+            let fetch = self.fetches.remove(index);
+            finish(transfers, store, fetch, collision).await;
+            return Ok(());
+        }
+        if fetch.peers.is_empty() && !self.queries.values().any(|h| *h == fetch.hash) {
+            let fetch = self.fetches.remove(index);
+            fail(transfers, fetch, "no peers left to fetch from");
+            return Ok(());
+        }
+        fetch.schedule(behaviour, chunk_size, &self.compression)
+    }
+
+    // Cancels a fetch, telling its peers to stop serving it, returning false if there is no such fetch
+    pub fn cancel(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        transfer_id: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(index) = self
+            .fetches
+            .iter()
+            .position(|f| f.transfer_id == Some(transfer_id))
+        else {
+            return Ok(false);
+        };
+        let fetch = self.fetches.remove(index);
+        for peer_id in &fetch.peers {
+            behaviour.send_req(
+                *peer_id,
+                FileRequest {
+                    filename: String::new(),
+                    offset: 0,
+                    length: 0,
+                    hash: Some(fetch.hash.clone()),
+                    compression: Vec::new(),
+                    digests: false,
+                    cancel: true,
+                },
+            )?;
+        }
+        transfers.set_status(transfer_id, TransferStatus::Cancelled);
+        println!("Fetch of {} cancelled", fetch.name());
+        drop(fetch.file);
+        let _ = std::fs::remove_file(&fetch.part_path);
+        Ok(true)
+    }
+
+    // Drops a peer that could not be reached and hands its chunks to the others
+    pub async fn handle_failure(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        request_id: OutboundRequestId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(index) = self
+            .fetches
+            .iter()
+            .position(|f| f.requests.contains_key(&request_id))
+        else {
+            return Ok(());
+        };
+        let fetch = &mut self.fetches[index];
+        if let Some((_, peer_id, _)) = fetch.requests.get(&request_id) {
+            fetch.drop_peer(*peer_id, "unreachable");
+        }
+
+        if fetch.peers.is_empty() && !self.queries.values().any(|h| *h == fetch.hash) {
+            let fetch = self.fetches.remove(index);
+            fail(transfers, fetch, "no peers left to fetch from");
+            return Ok(());
+        }
+        fetch.schedule(behaviour, self.chunk_size, &self.compression)
+    }
+}
+
+// Verifies the whole file against its hash and moves it into place through the store
+async fn finish(
+    transfers: &mut Transfers,
+    store: &mut BlobStore,
+    mut fetch: Fetch,
+    collision: Collision,
+) {
+    let file = fetch.file.take();
+    if let Err(e) = sendfile::sync(file).await {
+        fail(
+            transfers,
+            fetch,
+            &format!("could not write the file ({})", e),
+        );
+        return;
+    }
+    if !catalogue::hash_file(&fetch.part_path)
+        .await
+        .is_ok_and(|hash| hash == fetch.hash)
+    {
+        fail(transfers, fetch, "file is corrupted, hash does not match");
+        return;
+    }
+
+    let Some(path) = sendfile::target(collision, &fetch.path, &fetch.hash).await else {
+        if let Some(id) = fetch.transfer_id {
+            transfers.set_status(id, TransferStatus::Skipped);
+        }
+        println!(
+            "Skipped fetch of {}, {:?} already exists",
+            fetch.name(),
+            fetch.path
+        );
+        let _ = tokio::fs::remove_file(&fetch.part_path).await;
+        return;
+    };
+    let known = match store.save(&fetch.part_path, &fetch.hash, &path).await {
+        Ok(known) => known,
+        Err(e) => {
+            fail(
+                transfers,
+                fetch,
+                &format!("could not save the file ({})", e),
+            );
+            return;
+        }
+    };
+    if let Some(id) = fetch.transfer_id {
+        transfers.set_status(id, TransferStatus::Finished);
+    }
+    println!(
+        "Saved file to: {:?} ({} bytes from {} peers, sha256 {})",
+        path,
+        fetch.total_size,
+        fetch.peers.len() + fetch.dropped.len(),
+        fetch.hash
+    );
+    if known {
+        println!("Same content as a file already received, stored once");
+    }
+    if let Some(compression) = fetch
+        .transfer_id
+        .and_then(|id| transfers.get(id))
+        .and_then(Transfer::compression)
+    {
+        println!("Compressed with {}", compression);
+    }
+}
+
+// Reports a failed fetch and removes its partial file
+fn fail(transfers: &mut Transfers, fetch: Fetch, reason: &str) {
+    if let Some(id) = fetch.transfer_id {
+        transfers.set_status(id, TransferStatus::Failed(reason.to_string()));
+    }
+    println!("Fetch of {} failed: {}", fetch.name(), reason);
+    drop(fetch.file);
+    if !fetch.part_path.as_os_str().is_empty() {
+        let _ = std::fs::remove_file(&fetch.part_path);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::catalogue::{Catalogue, SharedFile};
+use crate::policy::{Decision, ServePolicy};
+use crate::sendfile::FileError;
+use crate::share::ShareRoot;
+
+use libp2p::request_response::OutboundRequestId;
+use libp2p::{request_response, swarm::NetworkBehaviour, PeerId};
+use serde::{Deserialize, Serialize};
+
+#[derive(NetworkBehaviour)]
+pub struct FileListBehaviour {
+    pub request_response:
+        libp2p::request_response::cbor::Behaviour<FileListRequest, FileListResponse>,
+}
+
+impl FileListBehaviour {
+    // Asks a peer for the files in their ByteBeam, or only those under a directory
+    pub fn send_req(
+        &mut self,
+        peer_id: PeerId,
+        dir: Option<String>,
+    ) -> Result<OutboundRequestId, Box<dyn std::error::Error>> {
+        Ok(self
+            .request_response
+            .send_request(&peer_id, FileListRequest { dir }))
+    }
+
+    // Answers a file list request with the listing made by list_files
+    pub fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        channel: request_response::ResponseChannel<FileListResponse>,
+        response: FileListResponse,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = &response {
+            println!("Refused to list your ByteBeam to {}: {}", peer_id, e);
+        }
+        if let Err(e) = self.request_response.send_response(channel, response) {
+            eprintln!("Error sending file list: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+// Scans the share for the files a peer may see, leaving out files hidden from them
+pub async fn list_files(
+    peer_id: PeerId,
+    request: &FileListRequest,
+    share: &ShareRoot,
+    catalogue: &mut Catalogue,
+    policy: &ServePolicy,
+) -> FileListResponse {
+    if let Decision::Deny(reason) = policy.check_peer(&peer_id) {
+        return Err(FileError::Refused(reason));
+    }
+
+    // A directory listing doubles as the manifest for a directory transfer
+    let prefix = match &request.dir {
+        Some(dir) => {
+            let dir = dir.trim_end_matches('/');
+            if !share.resolve(&peer_id, dir)?.is_dir() {
+                return Err(FileError::NotFound);
+            }
+            println!("Peer {} is fetching the manifest of {}", peer_id, dir);
+            format!("{}/", dir)
+        }
+        None => {
+            println!("Peer {} is listing your ByteBeam", peer_id);
+            String::new()
+        }
+    };
+
+    let files = catalogue.scan(share).await;
+    Ok(files
+        .into_iter()
+        .filter(|file| file.name.starts_with(&prefix))
+        // Rules are keyed by the name a symlink resolves to, so it cannot show a hidden file
+        .filter(|file| {
+            share
+                .canonical_name(&file.name)
+                .is_some_and(|name| policy.is_visible(&peer_id, &name))
+        })
+        .collect())
+}
+
+// file listing protocol for our app
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileListRequest {
+    // Only list the files under this directory, recursively
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
+pub type FileListResponse = Result<Vec<SharedFile>, FileError>;
@@ -0,0 +1,69 @@
+use libp2p::identity::Keypair;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const KEY_FILE: &str = "identity.key";
+
+// Returns the default path of the identity key file in a data directory
+pub fn key_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(KEY_FILE)
+}
+
+// Loads the keypair from disk, generating and storing a new one on first launch
+pub fn load_or_generate(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if !path.exists() {
+        println!("No identity found, generating a new one at {:?}", path);
+        return generate(path);
+    }
+
+    let bytes = fs::read(path)?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|e| format!("Identity file {:?} is invalid: {}", path, e))?;
+    Ok(keypair)
+}
+
+// Generates a new ed25519 keypair and writes it to disk, replacing any existing key
+pub fn generate(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let keypair = Keypair::generate_ed25519();
+    write_key(path, &keypair)?;
+    Ok(keypair)
+}
+
+// Writes the keypair so that only the current user can read it
+fn write_key(path: &Path, keypair: &Keypair) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let bytes = keypair.to_protobuf_encoding()?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        // Tighten permissions in case the file already existed with a looser mode
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(&bytes)?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut file = options.open(path)?;
+        file.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+// SHA-256 fingerprint of the public key, formatted as colon separated hex
+pub fn fingerprint(keypair: &Keypair) -> String {
+    let digest = Sha256::digest(keypair.public().encode_protobuf());
+    digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
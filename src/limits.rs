@@ -0,0 +1,267 @@
+use crate::catalogue::format_size;
+use crate::sendfile::{self, Direction, FileResponse};
+
+use libp2p::request_response::{OutboundRequestId, ResponseChannel};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tokio::time::{Duration, Instant};
+
+const LIMITS_FILE: &str = "limits.json";
+// Longest a chunk is held back, so the peer gets its answer well before the request times out
+const MAX_HOLD: Duration = Duration::from_secs(sendfile::REQUEST_TIMEOUT.as_secs() / 2);
+
+// Parses a rate such as 500KB, 2.5MB/s or 100000 into bytes per second, where "off" means no limit
+pub fn parse_rate(s: &str) -> Result<Option<u64>, String> {
+    let lower = s.trim().to_lowercase();
+    if lower == "off" {
+        return Ok(None);
+    }
+    let value = lower.strip_suffix("/s").unwrap_or(&lower);
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit.trim() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "g" | "gb" => 1e9,
+        _ => {
+            return Err(format!(
+                "Unknown unit in rate '{}', expected B, KB, MB or GB",
+                s
+            ))
+        }
+    };
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid rate '{}'", s))?;
+    match (number * multiplier) as u64 {
+        0 => Err("Rates must be at least 1 B/s, use off to remove a limit".to_string()),
+        rate => Ok(Some(rate)),
+    }
+}
+
+// Parses a rate like parse_rate, rejecting rates too slow to move a chunk before it may be held no longer
+pub fn parse_limit(s: &str, chunk_size: u64) -> Result<Option<u64>, String> {
+    let rate = parse_rate(s)?;
+    let min = chunk_size.div_ceil(MAX_HOLD.as_secs());
+    match rate {
+        Some(rate) if rate < min => Err(format!(
+            "Rates must be at least {} to move a {} chunk in time",
+            format_rate(Some(min)),
+            format_size(chunk_size)
+        )),
+        _ => Ok(rate),
+    }
+}
+
+pub fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", format_size(rate)),
+        None => "unlimited".to_string(),
+    }
+}
+
+// Bandwidth limits for file traffic in bytes per second, saved in the data directory.
+// Chat, whispers and the other small protocols are never limited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Limits {
+    // Over all peers together
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    // With each peer separately, uploads and downloads together
+    pub per_peer: Option<u64>,
+    // Replaces per_peer for particular peers, by peer id or nickname
+    pub peers: BTreeMap<String, u64>,
+}
+
+impl Limits {
+    fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(LIMITS_FILE)
+    }
+
+    // Loads the saved limits, falling back to no limits at all
+    pub fn load(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = Limits::path(data_dir);
+        if !path.exists() {
+            return Ok(Limits::default());
+        }
+        let limits = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| format!("Limits file {:?} is invalid: {}", path, e))?;
+        Ok(limits)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let path = Limits::path(data_dir);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Applies the limits set in the config over the saved ones
+    pub fn with_overrides(mut self, overrides: &LimitOverrides) -> Self {
+        if let Some(rate) = overrides.upload {
+            self.upload = rate;
+        }
+        if let Some(rate) = overrides.download {
+            self.download = rate;
+        }
+        if let Some(rate) = overrides.per_peer {
+            self.per_peer = rate;
+        }
+        for (peer, rate) in &overrides.peers {
+            match rate {
+                Some(rate) => self.peers.insert(peer.clone(), *rate),
+                None => self.peers.remove(peer),
+            };
+        }
+        self
+    }
+
+    // The limit on traffic with a peer, if there is one
+    pub fn for_peer(&self, peer_id: &PeerId, nickname: Option<&str>) -> Option<u64> {
+        self.peers
+            .get(&peer_id.to_string())
+            .or_else(|| nickname.and_then(|n| self.peers.get(n)))
+            .copied()
+            .or(self.per_peer)
+    }
+}
+
+// Limits set in the config, each replacing the saved one, where Some(None) removes a limit
+#[derive(Debug, Clone, Default)]
+pub struct LimitOverrides {
+    pub upload: Option<Option<u64>>,
+    pub download: Option<Option<u64>>,
+    pub per_peer: Option<Option<u64>>,
+    pub peers: BTreeMap<String, Option<u64>>,
+}
+
+// Paces traffic at a fixed rate by tracking when the link is next free
+struct Bucket {
+    rate: u64,
+    next_free: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Bucket {
+            rate,
+            next_free: now,
+        }
+    }
+
+    // Books bytes on the link, returning how long they have to wait for their turn.
+    // Bookings never start more than MAX_HOLD ahead, so when too much piles up the rate is
+    // exceeded rather than letting the peers' requests time out.
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        let start = self.next_free.max(now).min(now + MAX_HOLD);
+        self.next_free = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+        start - now
+    }
+}
+
+// File traffic waiting for its turn under the limits
+pub enum Held {
+    // A chunk we serve, answered once the upload limits allow
+    Response {
+        channel: ResponseChannel<FileResponse>,
+        response: FileResponse,
+    },
+    // A chunk we received, handled (and so the next one requested) once the download limits allow
+    Chunk {
+        request_id: OutboundRequestId,
+        response: FileResponse,
+    },
+}
+
+// Applies the limits by holding chunks back instead of sleeping, so the chat stays responsive
+pub struct Throttle {
+    limits: Limits,
+    upload: Option<Bucket>,
+    download: Option<Bucket>,
+    peers: HashMap<PeerId, Bucket>,
+    held: Vec<(Instant, Held)>,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        let now = Instant::now();
+        Throttle {
+            upload: limits.upload.map(|rate| Bucket::new(rate, now)),
+            download: limits.download.map(|rate| Bucket::new(rate, now)),
+            peers: HashMap::new(),
+            held: Vec::new(),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Switches to new limits, pacing from scratch
+    pub fn set_limits(&mut self, limits: Limits) {
+        *self = Throttle {
+            held: std::mem::take(&mut self.held),
+            ..Throttle::new(limits)
+        };
+    }
+
+    // Books a chunk exchanged with a peer, returning how long it has to be held
+    pub fn reserve(
+        &mut self,
+        direction: Direction,
+        peer_id: PeerId,
+        nickname: Option<&str>,
+        bytes: u64,
+    ) -> Duration {
+        // Errors carry no data and are never held
+        if bytes == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let global = match direction {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        };
+        let mut wait = global
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.reserve(bytes, now));
+        if let Some(rate) = self.limits.for_peer(&peer_id, nickname) {
+            let bucket = self
+                .peers
+                .entry(peer_id)
+                .or_insert_with(|| Bucket::new(rate, now));
+            bucket.rate = rate;
+            wait = wait.max(bucket.reserve(bytes, now));
+        }
+        wait
+    }
+
+    pub fn hold(&mut self, wait: Duration, held: Held) {
+        self.held.push((Instant::now() + wait, held));
+    }
+
+    // When the next held chunk is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.held.iter().map(|(due, _)| *due).min()
+    }
+
+    // Takes the held chunks that are due, in the order they fell due
+    pub fn take_due(&mut self) -> Vec<Held> {
+        let now = Instant::now();
+        let (mut due, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|(due, _)| *due <= now);
+        self.held = held;
+        due.sort_by_key(|(due, _)| *due);
+        due.into_iter().map(|(_, held)| held).collect()
+    }
+}
@@ -1,21 +1,164 @@
+mod catalogue;
 mod chatroom;
 mod commands;
+mod compression;
+mod config;
 mod directmessage;
+mod fetch;
+mod filelist;
+mod identity;
+mod limits;
+mod message;
 mod network;
+mod nicknames;
+mod offer;
+mod policy;
+mod rooms;
+mod search;
 mod sendfile;
+mod share;
+mod store;
+mod watcher;
 
+use clap::Parser;
+use config::{Config, DirectoryConfig, NetworkConfig, OutputMode, Settings, TransferConfig};
+use libp2p::Multiaddr;
 use std::error::Error;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+// Every option can also be set in the environment, which the command line overrides
+#[derive(Parser)]
+#[command(version, about = "Peer-to-peer chat and file sharing")]
+struct Cli {
+    /// Nickname to chat under, asked for on startup if left out
+    #[arg(short, long, env = "SWAPBYTES_NICKNAME")]
+    nickname: Option<String>,
+    /// Identity key file, created on first launch [default: <data dir>/identity.key]
+    #[arg(long, value_name = "FILE", env = "SWAPBYTES_IDENTITY")]
+    identity: Option<PathBuf>,
+    /// Address to listen on, may be repeated [default: any interface, random TCP and QUIC ports]
+    #[arg(
+        long,
+        value_name = "MULTIADDR",
+        env = "SWAPBYTES_LISTEN",
+        value_delimiter = ','
+    )]
+    listen: Vec<Multiaddr>,
+    /// Peer to connect to on startup, ending in /p2p/<peer id>, may be repeated
+    #[arg(long, value_name = "MULTIADDR", env = "SWAPBYTES_BOOTSTRAP", value_delimiter = ',', value_parser = config::parse_bootstrap)]
+    bootstrap: Vec<Multiaddr>,
+    /// Directory for the identity, serving policy and limits [default: $XDG_DATA_HOME/swapbytes]
+    #[arg(long, value_name = "DIR", env = "SWAPBYTES_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Config file to read instead of the default one
+    #[arg(long, value_name = "FILE", env = "SWAPBYTES_CONFIG")]
+    config: Option<PathBuf>,
+    /// Directory to share with peers, the ByteBeam
+    #[arg(long, value_name = "DIR", env = "SWAPBYTES_SHARE_DIR")]
+    share_dir: Option<PathBuf>,
+    /// Directory to save received files in, the ByteStream
+    #[arg(long, value_name = "DIR", env = "SWAPBYTES_DOWNLOAD_DIR")]
+    download_dir: Option<PathBuf>,
+    /// Directory for the content-addressed store of received files
+    #[arg(long, value_name = "DIR", env = "SWAPBYTES_STORE_DIR")]
+    store_dir: Option<PathBuf>,
+    /// Extra directory to share under an alias, may be repeated
+    #[arg(long = "share", value_name = "ALIAS=DIR", env = "SWAPBYTES_SHARES", value_delimiter = ',', value_parser = config::parse_share)]
+    shares: Vec<(String, PathBuf)>,
+    /// Size of the chunks files are requested in, in bytes
+    #[arg(long, value_name = "BYTES", env = "SWAPBYTES_CHUNK_SIZE")]
+    chunk_size: Option<u64>,
+    /// What to do when a download's name is taken: overwrite, rename, skip or skip-identical
+    #[arg(long, value_name = "STRATEGY", env = "SWAPBYTES_ON_CONFLICT")]
+    on_conflict: Option<String>,
+    /// How many downloads run at once
+    #[arg(long, value_name = "N", env = "SWAPBYTES_MAX_DOWNLOADS")]
+    max_downloads: Option<usize>,
+    /// How many uploads run at once
+    #[arg(long, value_name = "N", env = "SWAPBYTES_MAX_UPLOADS")]
+    max_uploads: Option<usize>,
+    /// Whether peers may compress the chunks they send: zstd or off
+    #[arg(long, value_name = "SETTING", env = "SWAPBYTES_COMPRESSION")]
+    compression: Option<String>,
+    /// Log filter for the networking internals, such as warn or libp2p_kad=debug [default: RUST_LOG, or error]
+    #[arg(long, value_name = "FILTER", env = "SWAPBYTES_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Whether to prompt and print usage hints, or run unattended [default: interactive]
+    #[arg(long, value_enum, env = "SWAPBYTES_OUTPUT")]
+    output: Option<OutputMode>,
+}
+
+impl Cli {
+    // The options that were given, as the top layer of settings
+    fn into_config(self) -> Config {
+        let strings = |addrs: Vec<Multiaddr>| {
+            (!addrs.is_empty()).then(|| addrs.iter().map(|a| a.to_string()).collect())
+        };
+        Config {
+            nickname: self.nickname,
+            identity: self.identity,
+            data_dir: self.data_dir,
+            log_level: self.log_level,
+            output: self.output,
+            network: NetworkConfig {
+                listen: strings(self.listen),
+                bootstrap: strings(self.bootstrap),
+                ..NetworkConfig::default()
+            },
+            gossipsub: Default::default(),
+            transfers: TransferConfig {
+                chunk_size: self.chunk_size,
+                on_conflict: self.on_conflict,
+                max_downloads: self.max_downloads,
+                max_uploads: self.max_uploads,
+                compression: self.compression,
+            },
+            limits: Default::default(),
+            directories: DirectoryConfig {
+                share: self.share_dir,
+                downloads: self.download_dir,
+                store: self.store_dir,
+                shares: self.shares.into_iter().collect(),
+            },
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Entry point for the application. User is not connected until they enter a nickname.
-    println!("Welcome to SwapBytes! Please enter your nickname to continue.");
-    println!("Enter your name:");
-    let mut input_name = String::new();
-    std::io::stdin().read_line(&mut input_name)?;
-    let nickname = input_name.trim().to_string();
-
-    chatroom::start_chat(nickname).await?;
+async fn main() {
+    // Errors are printed as they are, so a bad setting reads as a plain message
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    // Defaults, then the config file, then the environment and command line
+    let (file, config_file) = Config::load(cli.config.as_deref())?;
+    let mut config = file.merge(cli.into_config());
+
+    // Scripts and services have nobody to answer a prompt
+    let interactive = config.output.unwrap_or_default() == OutputMode::Interactive;
+    if config.nickname.is_none() && interactive && std::io::stdin().is_terminal() {
+        // User is not connected until they enter a nickname
+        println!("Welcome to SwapBytes! Please enter your nickname to continue.");
+        println!("Enter your name:");
+        let mut input_name = String::new();
+        std::io::stdin().read_line(&mut input_name)?;
+        config.nickname = Some(input_name.trim().to_string());
+    }
+    let settings = Settings::new(config, config_file)?;
+
+    // Logs go to stderr so they stay apart from the chat
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&settings.log_level)?)
+        .with_writer(std::io::stderr)
+        .init();
+
+    chatroom::start_chat(settings).await?;
 
     Ok(())
 }
@@ -0,0 +1,58 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Version written by this build, bumped when the envelope changes incompatibly
+pub const CHAT_MESSAGE_VERSION: u32 = 1;
+
+// Envelope published to gossipsub for every chat line.
+// Unknown fields are ignored when decoding, so new fields can be added with #[serde(default)].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub version: u32,
+    pub id: String,
+    pub sender: String,
+    pub timestamp: u64,
+    pub room: String,
+    pub body: String,
+}
+
+impl ChatMessage {
+    // Creates a message from this peer stamped with the current time
+    pub fn new(self_peer_id: &PeerId, sender: &str, room: &str, body: &str) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // Unique per peer and instant, short enough to show in logs
+        let digest = Sha256::digest(format!("{}:{}", self_peer_id, now.as_nanos()));
+        let id = digest[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        ChatMessage {
+            version: CHAT_MESSAGE_VERSION,
+            id,
+            sender: sender.to_string(),
+            timestamp: now.as_secs(),
+            room: room.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+
+    // Formats the timestamp as HH:MM in UTC
+    pub fn time(&self) -> String {
+        let secs = self.timestamp % 86_400;
+        format!("{:02}:{:02}", secs / 3600, (secs % 3600) / 60)
+    }
+}
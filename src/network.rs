@@ -1,4 +1,6 @@
 use crate::directmessage::DirectMessageBehaviour;
+use crate::filelist::FileListBehaviour;
+use crate::offer::OfferBehaviour;
 use crate::sendfile::SendFileBehaviour;
 
 use libp2p::kad::store::MemoryStore;
@@ -12,4 +14,6 @@ pub struct ChatBehaviour {
     pub kademlia: kad::Behaviour<MemoryStore>,
     pub request_response: SendFileBehaviour,
     pub direct_message: DirectMessageBehaviour,
+    pub file_list: FileListBehaviour,
+    pub offer: OfferBehaviour,
 }
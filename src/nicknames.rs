@@ -0,0 +1,67 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Local cache of peer nicknames, filled from the Kademlia name records
+#[derive(Default)]
+pub struct NicknameCache {
+    names: HashMap<PeerId, String>,
+}
+
+impl NicknameCache {
+    // Records the nickname a peer has published
+    pub fn insert(&mut self, peer_id: PeerId, nickname: String) {
+        self.names.insert(peer_id, nickname);
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&str> {
+        self.names.get(peer_id).map(String::as_str)
+    }
+
+    // Resolves a peer id or nickname to a peer id
+    pub fn resolve(&self, name: &str) -> Result<PeerId, String> {
+        if let Ok(peer_id) = PeerId::from_str(name) {
+            return Ok(peer_id);
+        }
+
+        // Exact nickname matches win over prefix matches
+        let mut matches = self.matching(|nickname| nickname == name);
+        if matches.is_empty() {
+            matches = self.matching(|nickname| nickname.starts_with(name));
+        }
+
+        match matches.len() {
+            0 => Err(format!("No peer with nickname '{}'", name)),
+            1 => Ok(matches[0]),
+            _ => Err(format!(
+                "Nickname '{}' matches multiple peers, use a peer id instead: {}",
+                name,
+                matches
+                    .iter()
+                    .map(|peer_id| format!("{} ({})", self.names[peer_id], peer_id))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    // Returns the peers whose nickname starts with the given prefix, sorted by nickname
+    pub fn starting_with(&self, prefix: &str) -> Vec<(&str, PeerId)> {
+        let mut peers = self
+            .names
+            .iter()
+            .filter(|(_, nickname)| nickname.starts_with(prefix))
+            .map(|(peer_id, nickname)| (nickname.as_str(), *peer_id))
+            .collect::<Vec<(&str, PeerId)>>();
+        peers.sort();
+        peers
+    }
+
+    fn matching(&self, predicate: impl Fn(&str) -> bool) -> Vec<PeerId> {
+        self.names
+            .iter()
+            .filter(|(_, nickname)| predicate(nickname))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+}
@@ -0,0 +1,108 @@
+use crate::catalogue;
+
+use libp2p::{request_response, swarm::NetworkBehaviour, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(NetworkBehaviour)]
+pub struct OfferBehaviour {
+    pub request_response: libp2p::request_response::cbor::Behaviour<OfferMessage, OfferAck>,
+}
+
+impl OfferBehaviour {
+    // Sends an offer or a reply to an offer to a peer
+    pub fn send_req(
+        &mut self,
+        peer_id: PeerId,
+        message: OfferMessage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.request_response.send_request(&peer_id, message);
+        Ok(())
+    }
+
+    // Processes an incoming offer or a peer's reply to one of our offers or requests
+    pub fn handle_request(
+        &mut self,
+        peer_id: PeerId,
+        message: OfferMessage,
+        channel: request_response::ResponseChannel<OfferAck>,
+        offers: &mut Offers,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match message {
+            OfferMessage::Offer(offer) => {
+                println!(
+                    "{} wants to beam you {} ({})",
+                    offer.sender,
+                    offer.filename,
+                    catalogue::format_size(offer.size)
+                );
+                let id = offers.add(peer_id, offer);
+                println!("Type /accept {} or /decline {}", id, id);
+            }
+            OfferMessage::Declined { filename } => {
+                println!("Peer {} declined your ByteBeam of {}", peer_id, filename);
+            }
+            OfferMessage::Approved { filename } => {
+                println!("Peer {} approved your bytestream of {}", peer_id, filename);
+            }
+            OfferMessage::Denied { filename } => {
+                println!("Peer {} denied your bytestream of {}", peer_id, filename);
+            }
+        }
+
+        if self
+            .request_response
+            .send_response(channel, OfferAck)
+            .is_err()
+        {
+            eprintln!("Error acknowledging offer from {}", peer_id);
+        }
+        Ok(())
+    }
+}
+
+// Offers received from peers that are waiting for /accept or /decline
+#[derive(Default)]
+pub struct Offers {
+    next_id: u64,
+    pending: BTreeMap<u64, (PeerId, FileOffer)>,
+}
+
+impl Offers {
+    fn add(&mut self, peer_id: PeerId, offer: FileOffer) -> u64 {
+        self.next_id += 1;
+        self.pending.insert(self.next_id, (peer_id, offer));
+        self.next_id
+    }
+
+    // Removes an offer once it has been answered
+    pub fn take(&mut self, id: u64) -> Option<(PeerId, FileOffer)> {
+        self.pending.remove(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u64, &(PeerId, FileOffer))> {
+        self.pending.iter()
+    }
+}
+
+// file offer protocol for our app
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub sender: String,
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OfferMessage {
+    Offer(FileOffer),
+    // Accepting needs no message, the recipient simply starts downloading the file
+    Declined { filename: String },
+    // Answers to a bytestream that was waiting for approval, see ServePolicy
+    Approved { filename: String },
+    Denied { filename: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferAck;
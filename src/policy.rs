@@ -0,0 +1,194 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const POLICY_FILE: &str = "policy.json";
+
+// How requests from peers that are not on any list are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServeMode {
+    // Serve every request
+    Auto,
+    // Ask the user to approve each file a peer requests
+    Prompt,
+    // Only serve peers on the allow list
+    AllowList,
+    // Serve everyone except peers on the deny list
+    DenyList,
+}
+
+impl FromStr for ServeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ServeMode::Auto),
+            "prompt" => Ok(ServeMode::Prompt),
+            "allowlist" => Ok(ServeMode::AllowList),
+            "denylist" => Ok(ServeMode::DenyList),
+            _ => Err(format!(
+                "Unknown mode '{}', expected auto, prompt, allowlist or denylist",
+                s
+            )),
+        }
+    }
+}
+
+// Who may see a particular shared file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Visibility {
+    Hidden,
+    // Peer ids, as nicknames can be claimed by anyone
+    Only(BTreeSet<String>),
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Hidden => write!(f, "hidden"),
+            Visibility::Only(peers) => {
+                write!(
+                    f,
+                    "only {}",
+                    peers.iter().cloned().collect::<Vec<_>>().join(", ")
+                )
+            }
+        }
+    }
+}
+
+// Outcome of checking a request against the policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny(String),
+    Prompt,
+}
+
+// Rules deciding which peers may fetch which files, saved in the data directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServePolicy {
+    pub mode: ServeMode,
+    // Peer ids, as nicknames can be claimed by anyone
+    pub allow: BTreeSet<String>,
+    pub deny: BTreeSet<String>,
+    // Files without a rule are visible to everyone the mode allows
+    pub visibility: BTreeMap<String, Visibility>,
+    // Files approved for a peer at a prompt or offered to them, for this session only
+    #[serde(skip)]
+    approved: HashSet<(PeerId, String)>,
+    #[serde(skip)]
+    prompts: BTreeMap<u64, (PeerId, String)>,
+    #[serde(skip)]
+    next_prompt: u64,
+}
+
+impl Default for ServePolicy {
+    fn default() -> Self {
+        ServePolicy {
+            mode: ServeMode::Auto,
+            allow: BTreeSet::new(),
+            deny: BTreeSet::new(),
+            visibility: BTreeMap::new(),
+            approved: HashSet::new(),
+            prompts: BTreeMap::new(),
+            next_prompt: 0,
+        }
+    }
+}
+
+impl ServePolicy {
+    fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(POLICY_FILE)
+    }
+
+    // Loads the saved policy, falling back to serving everyone
+    pub fn load(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = ServePolicy::path(data_dir);
+        if !path.exists() {
+            return Ok(ServePolicy::default());
+        }
+        let policy = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| format!("Policy file {:?} is invalid: {}", path, e))?;
+        Ok(policy)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let path = ServePolicy::path(data_dir);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Checks whether a peer may fetch a file
+    pub fn check(&self, peer_id: &PeerId, filename: &str) -> Decision {
+        // Files we offered or approved were chosen explicitly, nothing else applies
+        if self.approved.contains(&(*peer_id, filename.to_string())) {
+            return Decision::Allow;
+        }
+        if !self.is_visible(peer_id, filename) {
+            return Decision::Deny("file is not shared with you".to_string());
+        }
+        self.check_peer(peer_id)
+    }
+
+    // Checks whether a peer may use our ByteBeam at all, ignoring per-file rules
+    pub fn check_peer(&self, peer_id: &PeerId) -> Decision {
+        let listed = |list: &BTreeSet<String>| list.contains(&peer_id.to_string());
+
+        if listed(&self.deny) {
+            return Decision::Deny("you are on the deny list".to_string());
+        }
+        match self.mode {
+            ServeMode::Auto | ServeMode::DenyList => Decision::Allow,
+            ServeMode::AllowList if listed(&self.allow) => Decision::Allow,
+            ServeMode::AllowList => Decision::Deny("you are not on the allow list".to_string()),
+            ServeMode::Prompt if listed(&self.allow) => Decision::Allow,
+            ServeMode::Prompt => Decision::Prompt,
+        }
+    }
+
+    // Whether a file shows up for a peer in listings and can be requested by them
+    pub fn is_visible(&self, peer_id: &PeerId, filename: &str) -> bool {
+        match self.visibility.get(filename) {
+            None => true,
+            Some(Visibility::Hidden) => false,
+            Some(Visibility::Only(peers)) => peers.contains(&peer_id.to_string()),
+        }
+    }
+
+    // Whether every peer may see a file, so it can be announced to the whole room
+    pub fn is_public(&self, filename: &str) -> bool {
+        self.mode != ServeMode::AllowList && !self.visibility.contains_key(filename)
+    }
+
+    // Lets a peer fetch a file for the rest of the session
+    pub fn approve(&mut self, peer_id: PeerId, filename: &str) {
+        self.approved.insert((peer_id, filename.to_string()));
+    }
+
+    // Queues a prompt for the user, returning its id if it is new
+    pub fn add_prompt(&mut self, peer_id: PeerId, filename: &str) -> Option<u64> {
+        let pending = (peer_id, filename.to_string());
+        if self.prompts.values().any(|p| *p == pending) {
+            return None;
+        }
+        self.next_prompt += 1;
+        self.prompts.insert(self.next_prompt, pending);
+        Some(self.next_prompt)
+    }
+
+    pub fn take_prompt(&mut self, id: u64) -> Option<(PeerId, String)> {
+        self.prompts.remove(&id)
+    }
+
+    pub fn prompts(&self) -> impl Iterator<Item = (&u64, &(PeerId, String))> {
+        self.prompts.iter()
+    }
+}
@@ -0,0 +1,119 @@
+use libp2p::{gossipsub, PeerId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Room every peer joins on startup, published on the original "chat" topic
+pub const DEFAULT_ROOM: &str = "chat";
+
+// Tracks the rooms we are in and the rooms peers are advertising
+pub struct Rooms {
+    joined: BTreeSet<String>,
+    active: String,
+    advertised: HashMap<PeerId, BTreeSet<String>>,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Rooms {
+            joined: BTreeSet::from([DEFAULT_ROOM.to_string()]),
+            active: DEFAULT_ROOM.to_string(),
+            advertised: HashMap::new(),
+        }
+    }
+}
+
+impl Rooms {
+    // Gossipsub topic for a room
+    pub fn topic(room: &str) -> gossipsub::IdentTopic {
+        gossipsub::IdentTopic::new(room)
+    }
+
+    // Checks a room name is usable, '/' is reserved for internal topics
+    pub fn validate(room: &str) -> Result<(), String> {
+        if room.is_empty() || room.contains('/') || room.chars().any(char::is_whitespace) {
+            return Err(format!("Invalid room name: '{}'", room));
+        }
+        Ok(())
+    }
+
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    pub fn active_topic(&self) -> gossipsub::IdentTopic {
+        Rooms::topic(&self.active)
+    }
+
+    pub fn is_joined(&self, room: &str) -> bool {
+        self.joined.contains(room)
+    }
+
+    pub fn joined(&self) -> impl Iterator<Item = &String> {
+        self.joined.iter()
+    }
+
+    // Adds a room and makes it the active one
+    pub fn join(&mut self, room: &str) {
+        self.joined.insert(room.to_string());
+        self.active = room.to_string();
+    }
+
+    // Removes a room, moving the active room elsewhere if needed
+    pub fn leave(&mut self, room: &str) -> Result<(), String> {
+        if !self.joined.contains(room) {
+            return Err(format!("You are not in room '{}'", room));
+        }
+        if self.joined.len() == 1 {
+            return Err("You cannot leave your only room".to_string());
+        }
+
+        self.joined.remove(room);
+        if self.active == room {
+            if let Some(next) = self.joined.iter().next() {
+                self.active = next.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // Changes the room that chat lines are published to
+    pub fn switch(&mut self, room: &str) -> Result<(), String> {
+        if !self.joined.contains(room) {
+            return Err(format!("Join room '{}' before switching to it", room));
+        }
+        self.active = room.to_string();
+        Ok(())
+    }
+
+    // Records a room a peer has subscribed to
+    pub fn peer_subscribed(&mut self, peer_id: PeerId, topic: &gossipsub::TopicHash) {
+        if Rooms::validate(topic.as_str()).is_ok() {
+            self.advertised
+                .entry(peer_id)
+                .or_default()
+                .insert(topic.to_string());
+        }
+    }
+
+    pub fn peer_unsubscribed(&mut self, peer_id: PeerId, topic: &gossipsub::TopicHash) {
+        if let Some(rooms) = self.advertised.get_mut(&peer_id) {
+            rooms.remove(topic.as_str());
+            if rooms.is_empty() {
+                self.advertised.remove(&peer_id);
+            }
+        }
+    }
+
+    // Forgets the rooms of a peer that has gone away
+    pub fn peer_expired(&mut self, peer_id: &PeerId) {
+        self.advertised.remove(peer_id);
+    }
+
+    // Rooms advertised by peers with the number of peers in each
+    pub fn advertised(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for room in self.advertised.values().flatten() {
+            *counts.entry(room.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
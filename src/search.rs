@@ -0,0 +1,135 @@
+use crate::catalogue::SharedFile;
+
+use libp2p::kad::store::MemoryStore;
+use libp2p::kad::{self, QueryId};
+use libp2p::request_response::OutboundRequestId;
+use std::collections::{HashMap, HashSet};
+
+// Keywords shorter than this match too many files to be useful
+const MIN_KEYWORD_LEN: usize = 2;
+
+// Provider key for a file's content hash
+pub fn hash_key(hash: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("hash:{}", hash))
+}
+
+// Provider key for a filename keyword
+pub fn keyword_key(keyword: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("kw:{}", keyword))
+}
+
+// Splits a filename into lowercase alphanumeric keywords, e.g. "Q3_Report.pdf" -> q3, report, pdf
+pub fn keywords(name: &str) -> Vec<String> {
+    let mut words = name
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= MIN_KEYWORD_LEN)
+        .map(String::from)
+        .collect::<Vec<String>>();
+    words.sort();
+    words.dedup();
+    words
+}
+
+// Whether a term is a full SHA-256 content hash rather than keywords
+pub fn is_hash(term: &str) -> bool {
+    term.len() == 64 && term.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Whether a file matches every keyword of the search term
+pub fn matches(term: &str, file: &SharedFile) -> bool {
+    if is_hash(term) {
+        return file.hash.eq_ignore_ascii_case(term);
+    }
+    let name = keywords(&file.name);
+    keywords(term)
+        .iter()
+        .all(|word| name.iter().any(|n| n.contains(word.as_str())))
+}
+
+// Announces our shared files as provider records and tracks searches in progress
+#[derive(Default)]
+pub struct Search {
+    announced: HashSet<kad::RecordKey>,
+    // Hashes of the files in our store, which stay provided whatever happens to the share
+    stored: HashSet<kad::RecordKey>,
+    queries: HashMap<QueryId, (String, usize)>,
+    listings: HashMap<OutboundRequestId, String>,
+}
+
+impl Search {
+    // Provides the keys for the given files and stops providing keys that are no longer shared
+    pub fn announce(&mut self, kademlia: &mut kad::Behaviour<MemoryStore>, files: &[SharedFile]) {
+        let mut keys = HashSet::new();
+        for file in files {
+            keys.insert(hash_key(&file.hash));
+            keys.extend(keywords(&file.name).iter().map(|w| keyword_key(w)));
+        }
+
+        for key in self.announced.difference(&keys) {
+            if !self.stored.contains(key) {
+                kademlia.stop_providing(key);
+            }
+        }
+        for key in keys.difference(&self.announced) {
+            if let Err(e) = kademlia.start_providing(key.clone()) {
+                eprintln!("Failed to announce shared file: {:?}", e);
+            }
+        }
+        self.announced = keys;
+    }
+
+    // Provides the hashes of files we stored, so peers can fetch them from us
+    pub fn provide(&mut self, kademlia: &mut kad::Behaviour<MemoryStore>, hashes: &[String]) {
+        for hash in hashes {
+            let key = hash_key(hash);
+            if self.stored.insert(key.clone()) && !self.announced.contains(&key) {
+                if let Err(e) = kademlia.start_providing(key) {
+                    eprintln!("Failed to announce stored file: {:?}", e);
+                }
+            }
+        }
+    }
+
+    // Looks up the peers providing the term, using its longest keyword
+    pub fn start(
+        &mut self,
+        kademlia: &mut kad::Behaviour<MemoryStore>,
+        term: &str,
+    ) -> Result<(), String> {
+        let key = if is_hash(term) {
+            hash_key(&term.to_lowercase())
+        } else {
+            let words = keywords(term);
+            match words.iter().max_by_key(|w| w.len()) {
+                Some(word) => keyword_key(word),
+                None => return Err(format!("Search term '{}' has no keywords", term)),
+            }
+        };
+
+        let query_id = kademlia.get_providers(key);
+        self.queries.insert(query_id, (term.to_string(), 0));
+        Ok(())
+    }
+
+    // Records providers found for a search, returning its term if the query is ours
+    pub fn found_providers(&mut self, query_id: &QueryId, count: usize) -> Option<String> {
+        let (term, found) = self.queries.get_mut(query_id)?;
+        *found += count;
+        Some(term.clone())
+    }
+
+    // Forgets a finished provider lookup, returning its term and how many providers it found
+    pub fn finish_query(&mut self, query_id: &QueryId) -> Option<(String, usize)> {
+        self.queries.remove(query_id)
+    }
+
+    // Remembers a file list request sent to a provider on behalf of a search
+    pub fn add_listing(&mut self, request_id: OutboundRequestId, term: String) {
+        self.listings.insert(request_id, term);
+    }
+
+    pub fn take_listing(&mut self, request_id: &OutboundRequestId) -> Option<String> {
+        self.listings.remove(request_id)
+    }
+}
@@ -1,9 +1,37 @@
+use crate::catalogue::{self, Catalogue, SharedFile};
+use crate::compression::{self, Compression};
+use crate::share::{ShareError, ShareRoot};
+use crate::store::BlobStore;
+
+use libp2p::request_response::OutboundRequestId;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::{request_response, PeerId};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// Largest chunk a peer will serve, keeps responses well under the codec size limit
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+pub const DEFAULT_CHUNK_SIZE: u64 = 256 * 1024;
+pub const MIN_CHUNK_SIZE: u64 = 4 * 1024;
+
+// Bandwidth limits can hold a chunk back for a while, so wait longer than the default 10s
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub const DEFAULT_MAX_DOWNLOADS: usize = 3;
+pub const DEFAULT_MAX_UPLOADS: usize = 3;
+// How long to wait before asking a busy peer for a file again
+pub const BUSY_RETRY: Duration = Duration::from_secs(10);
+// An upload whose peer stops asking for chunks gives up its slot, allowing for held responses
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(REQUEST_TIMEOUT.as_secs() + 30);
 
 #[derive(NetworkBehaviour)]
 pub struct SendFileBehaviour {
@@ -16,72 +44,1691 @@ impl SendFileBehaviour {
         &mut self,
         peer_id: PeerId,
         request: FileRequest,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.request_response.send_request(&peer_id, request);
-        Ok(())
+    ) -> Result<OutboundRequestId, Box<dyn std::error::Error>> {
+        Ok(self.request_response.send_request(&peer_id, request))
     }
 
-    // Processes a file request
+    // Processes a file request into its answer, either a chunk or the reason it failed
     pub async fn process_req(
         &mut self,
+        peer_id: PeerId,
         request: FileRequest,
+        share: &ShareRoot,
+        catalogue: &mut Catalogue,
+        transfers: &mut Transfers,
+        uploads: &mut Uploads,
+    ) -> FileResponse {
+        // Digests read the whole file, so they take a slot like the chunks that follow them
+        if let Err(e) = uploads.admit(transfers, peer_id, request.upload_key(), request.offset) {
+            eprintln!("Refused {} to {}: {}", request.filename, peer_id, e);
+            return Err(e);
+        }
+        if request.offset == 0 {
+            println!("Received request for file: {}", request.filename);
+        }
+
+        // Read the requested chunk and send response
+        let res = self.read_chunk(share, catalogue, &peer_id, &request).await;
+        match res {
+            // Digests are only the prelude to the chunks of a multi-source fetch
+            Ok(chunk) if request.digests => {
+                println!("Sent chunk digests of {} to {}", chunk.filename, peer_id);
+                // An empty file has no chunks to follow
+                if chunk.digests.is_empty() {
+                    uploads.release(peer_id, request.upload_key());
+                }
+                Ok(chunk)
+            }
+            Ok(chunk) => {
+                let id = transfers.upload_progress(peer_id, &request, &chunk);
+                if transfers
+                    .get(id)
+                    .is_some_and(|t| t.status == TransferStatus::Finished)
+                {
+                    println!("Finished sending file: {}", request.filename);
+                    uploads.release(peer_id, request.upload_key());
+                }
+                // Progress counts file bytes, so compress only once it is recorded
+                let chunk = chunk.compress(&request.compression);
+                transfers.wire(id, chunk.data.len() as u64, chunk.encoding);
+                Ok(chunk)
+            }
+            Err(e) => {
+                eprintln!("Could not send {} to {}: {}", request.filename, peer_id, e);
+                uploads.release(peer_id, request.upload_key());
+                transfers.add(
+                    Direction::Upload,
+                    peer_id,
+                    &request.filename,
+                    TransferStatus::Failed(e.to_string()),
+                );
+                Err(e)
+            }
+        }
+    }
+
+    pub fn respond(
+        &mut self,
         channel: request_response::ResponseChannel<FileResponse>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let filename = request.0.clone();
-        println!("Received request for file: {}", filename);
+        response: FileResponse,
+    ) {
+        if let Err(e) = self.request_response.send_response(channel, response) {
+            eprintln!("Error sending response: {:?}", e);
+        }
+    }
+
+    // Answers a request the serving policy does not allow without touching the file
+    pub fn refuse(
+        &mut self,
+        peer_id: PeerId,
+        request: &FileRequest,
+        channel: request_response::ResponseChannel<FileResponse>,
+        error: FileError,
+    ) {
+        eprintln!("Refused {} to {}: {}", request.filename, peer_id, error);
+        self.respond(channel, Err(error));
+    }
+
+    // Reads the requested range of a file, along with the file size and hash
+    async fn read_chunk(
+        &self,
+        share: &ShareRoot,
+        catalogue: &mut Catalogue,
+        peer_id: &PeerId,
+        request: &FileRequest,
+    ) -> Result<FileChunk, FileError> {
+        if request.length > MAX_CHUNK_SIZE {
+            return Err(FileError::TooLarge);
+        }
+
+        // Resolve inside the share so peers cannot reach any other file
+        let path = share.resolve(peer_id, &request.filename)?;
+        if !path.is_file() {
+            return Err(FileError::NotFound);
+        }
+
+        // The hash is cached, so this only reads the whole file once per change
+        let (_, _, hash) = catalogue.hash(&path).await?;
+
+        if request.digests {
+            // Tiny chunks would make a digest list far larger than the file
+            if request.length < MIN_CHUNK_SIZE {
+                return Err(FileError::Refused(format!(
+                    "chunks must be at least {} for digests",
+                    catalogue::format_size(MIN_CHUNK_SIZE)
+                )));
+            }
+            let (total_size, digests) = chunk_digests(&path, request.length).await?;
+            return Ok(FileChunk {
+                filename: request.filename.clone(),
+                offset: 0,
+                total_size,
+                hash,
+                data: Vec::new(),
+                digests,
+                encoding: None,
+            });
+        }
+
+        // Only the requested range is held in memory
+        let mut handle = File::open(&path).await?;
+        let total_size = handle.metadata().await?.len();
+        let mut data = Vec::new();
+        handle.seek(SeekFrom::Start(request.offset)).await?;
+        handle.take(request.length).read_to_end(&mut data).await?;
+        Ok(FileChunk {
+            filename: request.filename.clone(),
+            offset: request.offset,
+            total_size,
+            hash,
+            data,
+            digests: Vec::new(),
+            encoding: None,
+        })
+    }
+}
+
+// Hashes a file in chunks of the given length, returning its size and the chunk digests
+async fn chunk_digests(path: &Path, length: u64) -> Result<(u64, Vec<String>), std::io::Error> {
+    let mut file = File::open(path).await?;
+    let mut digests = Vec::new();
+    let mut total_size = 0;
+    loop {
+        let mut data = Vec::new();
+        (&mut file).take(length).read_to_end(&mut data).await?;
+        if data.is_empty() {
+            break;
+        }
+        total_size += data.len() as u64;
+        digests.push(catalogue::to_hex(&Sha256::digest(&data)));
+    }
+    Ok((total_size, digests))
+}
+
+// A file being pulled from a peer one chunk at a time
+struct Download {
+    // Id of the entry in Transfers that reports this download's progress
+    transfer_id: u64,
+    peer_id: PeerId,
+    filename: String,
+    path: PathBuf,
+    part_path: PathBuf,
+    // Sidecar recording how far the .part file got, see PartialState
+    state_path: PathBuf,
+    file: Option<File>,
+    offset: u64,
+    total_size: u64,
+    priority: Priority,
+    // Set while waiting to ask a busy peer again
+    retry_at: Option<Instant>,
+    // Hash of the bytes received so far and the hash the sender says the file has
+    hasher: Sha256,
+    expected_hash: Option<String>,
+    // Set when loaded from disk, the .part file must match it before resuming
+    partial_hash: Option<String>,
+}
+
+impl Download {
+    fn new(transfer_id: u64, peer_id: PeerId, filename: &str, path: PathBuf) -> Self {
+        let mut part_path = path.clone().into_os_string();
+        part_path.push(".part");
+        let mut state_path = part_path.clone();
+        state_path.push(".json");
+
+        Download {
+            transfer_id,
+            peer_id,
+            filename: filename.to_string(),
+            path,
+            part_path: PathBuf::from(part_path),
+            state_path: PathBuf::from(state_path),
+            file: None,
+            offset: 0,
+            total_size: 0,
+            priority: Priority::Normal,
+            retry_at: None,
+            hasher: Sha256::new(),
+            expected_hash: None,
+            partial_hash: None,
+        }
+    }
 
-        // Read file and send response
-        match self.read_file(filename.clone()).await {
-            Ok(bytes) => {
-                let res = FileResponse {
+    // Restores a download from its sidecar file
+    fn load(
+        state_path: &Path,
+        dir: &Path,
+        transfers: &mut Transfers,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let state: PartialState = serde_json::from_slice(&std::fs::read(state_path)?)?;
+        let peer_id = PeerId::from_str(&state.peer_id)?;
+        let transfer_id = transfers.add(
+            Direction::Download,
+            peer_id,
+            &state.filename,
+            TransferStatus::Paused,
+        );
+        transfers.progress(transfer_id, state.offset, state.total_size);
+        let path = state
+            .path
+            .or_else(|| download_path(dir, &state.filename))
+            .ok_or_else(|| format!("invalid file name '{}'", state.filename))?;
+        let mut download = Download::new(transfer_id, peer_id, &state.filename, path);
+        download.priority = state.priority;
+        download.offset = state.offset;
+        download.total_size = state.total_size;
+        download.expected_hash = state.expected_hash;
+        download.partial_hash = Some(state.partial_hash);
+        Ok(download)
+    }
+
+    // Records progress so the download can resume after a disconnect or restart
+    fn save_state(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let state = PartialState {
+            peer_id: self.peer_id.to_string(),
+            filename: self.filename.clone(),
+            path: Some(self.path.clone()),
+            priority: self.priority,
+            offset: self.offset,
+            total_size: self.total_size,
+            expected_hash: self.expected_hash.clone(),
+            partial_hash: catalogue::to_hex(&self.hasher.clone().finalize()),
+        };
+        std::fs::write(&self.state_path, serde_json::to_vec(&state)?)?;
+        Ok(())
+    }
+
+    // Reopens the .part file at the current offset, checking it against the saved hash first
+    fn reopen(&mut self) -> Result<(), std::io::Error> {
+        if self.offset == 0 {
+            return Ok(());
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.part_path)?;
+
+        if let Some(partial_hash) = self.partial_hash.take() {
+            let mut hasher = Sha256::new();
+            let read = std::io::copy(&mut (&mut file).take(self.offset), &mut hasher)?;
+            if read != self.offset || catalogue::to_hex(&hasher.clone().finalize()) != partial_hash
+            {
+                println!(
+                    "Partial file for {} does not match its saved state, starting over",
+                    self.filename
+                );
+                self.offset = 0;
+                self.expected_hash = None;
+                self.hasher = Sha256::new();
+                file.set_len(0)?;
+                return Ok(());
+            }
+            self.hasher = hasher;
+        }
+
+        // Anything past the last recorded offset was never verified
+        file.set_len(self.offset)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        self.file = Some(File::from_std(file));
+        Ok(())
+    }
+
+    // Appends a chunk to the .part file, creating it on the first chunk
+    async fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        if self.file.is_none() {
+            if let Some(parent) = self.part_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            self.file = Some(File::create(&self.part_path).await?);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    fn progress(&self) -> String {
+        if self.total_size == 0 {
+            return "not started".to_string();
+        }
+        format!(
+            "{}% ({} of {})",
+            self.offset * 100 / self.total_size,
+            catalogue::format_size(self.offset),
+            catalogue::format_size(self.total_size)
+        )
+    }
+}
+
+// Where a single requested file is saved, flattened so peers cannot pick the directory.
+// None for names that are not a file inside it, such as "", "." and "..".
+pub fn download_path(dir: &Path, filename: &str) -> Option<PathBuf> {
+    let flat = filename.replace(&['/', '\\'][..], "_"); // prevent path traversal
+    if flat.contains('\0') {
+        return None;
+    }
+    let mut components = Path::new(&flat).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(dir.join(name)),
+        _ => None,
+    }
+}
+
+// Where a file of a directory transfer is saved, keeping the tree but only plain names
+fn directory_entry_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let relative = Path::new(name);
+    if name.contains('\\')
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(dir.join(relative))
+}
+
+// What to do when a download's name is already taken in the download directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    Overwrite,
+    // Save under a free name such as "report (1).pdf"
+    Rename,
+    Skip,
+    // Skip if the existing file has the same contents, otherwise rename
+    SkipIdentical,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Collision::Overwrite),
+            "rename" => Ok(Collision::Rename),
+            "skip" => Ok(Collision::Skip),
+            "skip-identical" => Ok(Collision::SkipIdentical),
+            _ => Err(format!(
+                "Unknown conflict strategy '{}', expected overwrite, rename, skip or skip-identical",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Collision::Overwrite => write!(f, "overwrite"),
+            Collision::Rename => write!(f, "rename"),
+            Collision::Skip => write!(f, "skip"),
+            Collision::SkipIdentical => write!(f, "skip-identical"),
+        }
+    }
+}
+
+// Order in which waiting downloads get a free slot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!(
+                "Unknown priority '{}', expected low, normal or high",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::High => write!(f, "high"),
+        }
+    }
+}
+
+// Tracks downloads by the request id of their in-flight chunk.
+// Only one chunk per download is requested at a time, so memory use stays bounded
+// and a slow disk naturally slows down the sender.
+// At most max_active downloads run at once, the rest wait for a slot by priority.
+pub struct Downloads {
+    // Where received files are saved
+    dir: PathBuf,
+    chunk_size: u64,
+    max_active: usize,
+    in_flight: HashMap<OutboundRequestId, Download>,
+    // Downloads interrupted by a network failure, waiting for their peer to come back
+    paused: Vec<Download>,
+    // Downloads waiting for a free slot, or for a busy peer to be asked again
+    waiting: Vec<Download>,
+    collision: Collision,
+    // Encodings offered to senders, empty when compression is turned off
+    compression: Vec<Compression>,
+    // Manifest requests for directory transfers, by the request id of the file list request
+    directories: HashMap<OutboundRequestId, String>,
+}
+
+impl Downloads {
+    pub fn new(
+        dir: PathBuf,
+        chunk_size: u64,
+        max_active: usize,
+        collision: Collision,
+        compression: &[Compression],
+    ) -> Self {
+        Downloads {
+            dir,
+            chunk_size: chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+            max_active: max_active.max(1),
+            in_flight: HashMap::new(),
+            paused: Vec::new(),
+            waiting: Vec::new(),
+            collision,
+            compression: compression.to_vec(),
+            directories: HashMap::new(),
+        }
+    }
+
+    // Picks up the unfinished downloads left by a previous run
+    pub fn load_partial(&mut self, transfers: &mut Transfers) {
+        // Files of directory transfers are saved in subdirectories
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    dirs.push(path);
+                    continue;
+                }
+                if !path.to_string_lossy().ends_with(".part.json") {
+                    continue;
+                }
+                match Download::load(&path, &self.dir, transfers) {
+                    Ok(download) => self.paused.push(download),
+                    Err(e) => eprintln!("Could not load partial download {:?}: {}", path, e),
+                }
+            }
+        }
+        if !self.paused.is_empty() {
+            println!(
+                "Found {} unfinished downloads, use /resume to list them",
+                self.paused.len()
+            );
+        }
+    }
+
+    // Queues a file for download, it starts straight away if a slot is free
+    pub fn start(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+        filename: &str,
+        priority: Priority,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = download_path(&self.dir, filename) else {
+            return Err(format!("Cannot save a file named '{}'", filename).into());
+        };
+        // Asking for a file again picks up where the last attempt stopped
+        let mut download = match self
+            .paused
+            .iter()
+            .position(|d| d.peer_id == peer_id && d.filename == filename)
+        {
+            Some(index) => self.paused.remove(index),
+            None => {
+                let transfer_id = transfers.add(
+                    Direction::Download,
+                    peer_id,
                     filename,
-                    data: bytes,
-                };
-                if let Err(e) = self.request_response.send_response(channel, res) {
-                    eprintln!("Error sending response: {:?}", e);
+                    TransferStatus::Queued,
+                );
+                Download::new(transfer_id, peer_id, filename, path)
+            }
+        };
+        download.priority = priority;
+        let transfer_id = download.transfer_id;
+        self.enqueue(transfers, download);
+        self.start_waiting(behaviour, transfers);
+
+        if self.waiting.iter().any(|d| d.transfer_id == transfer_id) {
+            println!(
+                "Queued bytestream for {}, all {} download slots are in use",
+                filename, self.max_active
+            );
+        }
+        Ok(())
+    }
+
+    // Remembers a manifest request sent for a directory transfer
+    pub fn add_directory(&mut self, request_id: OutboundRequestId, dir: String) {
+        self.directories.insert(request_id, dir);
+    }
+
+    pub fn take_directory(&mut self, request_id: &OutboundRequestId) -> Option<String> {
+        self.directories.remove(request_id)
+    }
+
+    // Saves the manifest of a directory and queues its files
+    pub fn start_directory(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+        dir: &str,
+        files: Vec<SharedFile>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(root) = directory_entry_path(&self.dir, dir) else {
+            return Err(format!("Invalid directory name '{}'", dir).into());
+        };
+        if files.is_empty() {
+            println!("Directory {} from {} has no files to fetch", dir, peer_id);
+            return Ok(());
+        }
+
+        // A peer could send entries outside the directory, or with traversal in their names
+        let prefix = format!("{}/", dir);
+        let (files, invalid): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| {
+            file.name.starts_with(&prefix) && directory_entry_path(&self.dir, &file.name).is_some()
+        });
+        for file in invalid {
+            eprintln!(
+                "Ignoring invalid manifest entry from {}: {}",
+                peer_id, file.name
+            );
+        }
+
+        let mut manifest_path = root.clone().into_os_string();
+        manifest_path.push(".manifest.json");
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(&manifest_path, serde_json::to_vec_pretty(&files)?)?;
+        println!(
+            "Bytestreaming directory {} from {}: {} files, {} (manifest saved to {:?})",
+            dir,
+            peer_id,
+            files.len(),
+            catalogue::format_size(files.iter().map(|f| f.size).sum()),
+            manifest_path
+        );
+
+        for file in files {
+            let Some(path) = directory_entry_path(&self.dir, &file.name) else {
+                continue;
+            };
+            let transfer_id = transfers.add(
+                Direction::Download,
+                peer_id,
+                &file.name,
+                TransferStatus::Queued,
+            );
+            let mut download = Download::new(transfer_id, peer_id, &file.name, path);
+            // The manifest hash is checked against every chunk like the sender's own
+            download.expected_hash = Some(file.hash);
+            self.waiting.push(download);
+        }
+        self.start_waiting(behaviour, transfers);
+        Ok(())
+    }
+
+    fn enqueue(&mut self, transfers: &mut Transfers, download: Download) {
+        transfers.set_status(download.transfer_id, TransferStatus::Queued);
+        self.waiting.push(download);
+    }
+
+    // Starts waiting downloads while slots are free, highest priority first and then oldest first
+    fn start_waiting(&mut self, behaviour: &mut SendFileBehaviour, transfers: &mut Transfers) {
+        let now = Instant::now();
+        while self.in_flight.len() < self.max_active {
+            let Some(index) = self
+                .waiting
+                .iter()
+                .enumerate()
+                .filter(|(_, d)| d.retry_at.is_none_or(|at| at <= now))
+                .max_by_key(|(_, d)| (d.priority, Reverse(d.transfer_id)))
+                .map(|(index, _)| index)
+            else {
+                break;
+            };
+            let download = self.waiting.remove(index);
+            let (transfer_id, filename) = (download.transfer_id, download.filename.clone());
+            if let Err(e) = self.begin(behaviour, transfers, download) {
+                eprintln!("Could not start bytestream for {}: {}", filename, e);
+                transfers.set_status(transfer_id, TransferStatus::Failed(e.to_string()));
+            }
+        }
+    }
+
+    // When a download waiting on a busy peer is due to ask again, if there is a slot for it
+    pub fn next_retry(&self) -> Option<Instant> {
+        if self.in_flight.len() >= self.max_active {
+            return None;
+        }
+        self.waiting.iter().filter_map(|d| d.retry_at).min()
+    }
+
+    // Asks busy peers again for the downloads that are due
+    pub fn retry(&mut self, behaviour: &mut SendFileBehaviour, transfers: &mut Transfers) {
+        self.start_waiting(behaviour, transfers);
+    }
+
+    // Writes a received chunk and requests the next one
+    pub async fn handle_response(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        store: &mut BlobStore,
+        request_id: OutboundRequestId,
+        response: FileResponse,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(download) = self.in_flight.remove(&request_id) else {
+            return Ok(());
+        };
+        let result = self
+            .receive(behaviour, transfers, store, download, response)
+            .await;
+
+        // A download that finished, failed or paused leaves its slot to the next one
+        self.start_waiting(behaviour, transfers);
+        result
+    }
+
+    async fn receive(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        store: &mut BlobStore,
+        mut download: Download,
+        response: FileResponse,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Nothing is written to disk unless the peer actually sent the file
+        let response = match response {
+            Ok(chunk) => {
+                transfers.wire(
+                    download.transfer_id,
+                    chunk.data.len() as u64,
+                    chunk.encoding,
+                );
+                match chunk.decompress(self.chunk_size) {
+                    Ok(chunk) => chunk,
+                    Err(reason) => {
+                        abort(transfers, download, &reason).await;
+                        return Ok(());
+                    }
                 }
             }
+            Err(FileError::AwaitingApproval) => {
+                self.pause(transfers, download, "is waiting for the peer to approve it");
+                return Ok(());
+            }
+            Err(FileError::Busy) => {
+                println!(
+                    "Peer {} is busy, asking for {} again in {}s",
+                    download.peer_id,
+                    download.filename,
+                    BUSY_RETRY.as_secs()
+                );
+                download.retry_at = Some(Instant::now() + BUSY_RETRY);
+                self.enqueue(transfers, download);
+                return Ok(());
+            }
+            Err(FileError::Cancelled) => {
+                cancel(transfers, download, "the peer").await;
+                return Ok(());
+            }
             Err(e) => {
-                eprintln!("Error selecting file: {:?}", e);
+                abort(transfers, download, &e.to_string()).await;
+                return Ok(());
+            }
+        };
+
+        // The file changed on the sender's side while we were downloading it
+        if let Some(expected) = &download.expected_hash {
+            if *expected != response.hash {
+                abort(transfers, download, "file changed during transfer").await;
+                return Ok(());
             }
         }
+
+        if response.offset != download.offset {
+            let reason = format!(
+                "expected offset {} but got {}",
+                download.offset, response.offset
+            );
+            abort(transfers, download, &reason).await;
+            return Ok(());
+        }
+
+        // Once the hash is known, a file we would skip anyway need not be downloaded
+        if download.offset == 0
+            && target(self.collision, &download.path, &response.hash)
+                .await
+                .is_none()
+        {
+            skip(transfers, download).await;
+            return Ok(());
+        }
+
+        // A full disk fails this download, not the whole session
+        if let Err(e) = download.write(&response.data).await {
+            let reason = format!("could not write the file ({})", e);
+            abort(transfers, download, &reason).await;
+            return Ok(());
+        }
+        download.hasher.update(&response.data);
+        download.offset += response.data.len() as u64;
+        download.total_size = response.total_size;
+        download.expected_hash = Some(response.hash);
+        transfers.progress(download.transfer_id, download.offset, download.total_size);
+
+        if download.offset >= download.total_size {
+            return self.finish(transfers, store, download).await;
+        }
+        if response.data.is_empty() {
+            abort(transfers, download, "peer stopped sending").await;
+            return Ok(());
+        }
+        if let Err(e) = download.save_state() {
+            eprintln!("Could not save progress of {}: {}", download.filename, e);
+        }
+        self.request_next(behaviour, download)
+    }
+
+    // Pauses a download whose chunk request failed, keeping what has been received
+    pub fn handle_failure(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        request_id: OutboundRequestId,
+    ) {
+        if let Some(download) = self.in_flight.remove(&request_id) {
+            self.pause(transfers, download, "will resume when the peer is back");
+            self.start_waiting(behaviour, transfers);
+        }
+    }
+
+    fn pause(&mut self, transfers: &mut Transfers, mut download: Download, reason: &str) {
+        transfers.set_status(download.transfer_id, TransferStatus::Paused);
+        println!(
+            "Bytestream for {} paused at {}, it {}",
+            download.filename,
+            download.progress(),
+            reason
+        );
+        download.file = None;
+        if let Err(e) = download.save_state() {
+            eprintln!("Could not save progress of {}: {}", download.filename, e);
+        }
+        self.paused.push(download);
+    }
+
+    // Gives up on a paused download, e.g. when the peer denied it
+    pub async fn drop_paused(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+        filename: &str,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(index) = self
+            .paused
+            .iter()
+            .position(|d| d.peer_id == peer_id && d.filename == filename)
+        {
+            let download = self.paused.remove(index);
+            abort(transfers, download, reason).await;
+        }
+        self.start_waiting(behaviour, transfers);
         Ok(())
     }
 
-    // Reads the file
-    async fn read_file(&self, filename: String) -> Result<Vec<u8>, std::io::Error> {
-        let path = Path::new("files").join("bytebeam").join(&filename);
-        let mut bytes = Vec::new();
+    // Resumes every paused download from a peer that has come back
+    pub fn resume_peer(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+    ) {
+        let (resumable, paused) = std::mem::take(&mut self.paused)
+            .into_iter()
+            .partition(|d| d.peer_id == peer_id);
+        self.paused = paused;
 
-        // Check if the file exists and is a valid file
-        if !path.exists() || !path.is_file() {
-            eprintln!("File does not exist or not valid - {:?}", path);
-            return Ok(bytes);
+        for download in resumable {
+            self.enqueue(transfers, download);
         }
+        self.start_waiting(behaviour, transfers);
+    }
 
-        // Open and read the file asynchronously
-        let file_handle = File::open(&path).await;
+    // Resumes a paused download by name, only from the given peer if there is one
+    pub fn resume_file(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        peer_id: Option<PeerId>,
+        filename: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(index) = self.paused.iter().position(|d| {
+            d.filename == filename && peer_id.is_none_or(|peer_id| d.peer_id == peer_id)
+        }) else {
+            return Err(format!("No unfinished download named '{}'", filename).into());
+        };
+        let download = self.paused.remove(index);
+        self.enqueue(transfers, download);
+        self.start_waiting(behaviour, transfers);
+        Ok(())
+    }
+
+    // Describes the paused downloads for /resume
+    pub fn unfinished(&self) -> Vec<String> {
+        self.paused
+            .iter()
+            .map(|d| format!("{} from {}: {}", d.filename, d.peer_id, d.progress()))
+            .collect()
+    }
 
-        match file_handle {
-            Ok(mut handle) => {
-                if let Err(read_error) = handle.read_to_end(&mut bytes).await {
-                    eprintln!("Failed to read file: {:?} - {}", path, read_error);
+    // Lists the downloads waiting for a slot, in the order they will start
+    pub fn waiting(&self) -> Vec<String> {
+        let mut waiting = self.waiting.iter().collect::<Vec<_>>();
+        waiting.sort_by_key(|d| (Reverse(d.priority), d.transfer_id));
+        waiting
+            .iter()
+            .map(|d| {
+                format!(
+                    "#{} {} from {} ({} priority){}",
+                    d.transfer_id,
+                    d.filename,
+                    d.peer_id,
+                    d.priority,
+                    if d.retry_at.is_some() {
+                        ", peer is busy"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .collect()
+    }
+
+    // Number of downloads running and the most that may run at once
+    pub fn slots(&self) -> (usize, usize) {
+        (self.in_flight.len(), self.max_active)
+    }
+
+    pub fn set_max_active(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        max_active: usize,
+    ) {
+        self.max_active = max_active.max(1);
+        self.start_waiting(behaviour, transfers);
+    }
+
+    // Changes the priority of a download, which decides when it gets a slot
+    pub fn set_priority(&mut self, transfer_id: u64, priority: Priority) -> bool {
+        match self
+            .in_flight
+            .values_mut()
+            .chain(self.paused.iter_mut())
+            .chain(self.waiting.iter_mut())
+            .find(|d| d.transfer_id == transfer_id)
+        {
+            Some(download) => {
+                download.priority = priority;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Cancels a download and removes its partial file, returning false if there is no such download
+    pub async fn cancel(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        transfer_id: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let running = self
+            .in_flight
+            .iter()
+            .find(|(_, d)| d.transfer_id == transfer_id)
+            .map(|(request_id, _)| *request_id);
+        let download = if let Some(request_id) = running {
+            self.in_flight.remove(&request_id)
+        } else if let Some(index) = self
+            .paused
+            .iter()
+            .position(|d| d.transfer_id == transfer_id)
+        {
+            Some(self.paused.remove(index))
+        } else if let Some(index) = self
+            .waiting
+            .iter()
+            .position(|d| d.transfer_id == transfer_id)
+        {
+            Some(self.waiting.remove(index))
+        } else {
+            None
+        };
+        let Some(download) = download else {
+            return Ok(false);
+        };
+
+        // A running download holds an upload slot on the peer, which it can now give up
+        if running.is_some() {
+            behaviour.send_req(
+                download.peer_id,
+                FileRequest {
+                    filename: download.filename.clone(),
+                    offset: download.offset,
+                    length: 0,
+                    hash: None,
+                    compression: Vec::new(),
+                    digests: false,
+                    cancel: true,
+                },
+            )?;
+        }
+        cancel(transfers, download, "you").await;
+        self.start_waiting(behaviour, transfers);
+        Ok(true)
+    }
+
+    // Starts or resumes a download by requesting its next chunk
+    fn begin(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        transfers: &mut Transfers,
+        mut download: Download,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        download.retry_at = None;
+        // A download sent back by a busy peer still has its file open
+        if download.file.is_none() {
+            download.reopen()?;
+        }
+        transfers.progress(download.transfer_id, download.offset, download.total_size);
+        transfers.set_status(download.transfer_id, TransferStatus::Active);
+        if download.offset > 0 {
+            println!(
+                "Resuming bytestream for {} at {}",
+                download.filename,
+                download.progress()
+            );
+        }
+        self.request_next(behaviour, download)
+    }
+
+    fn request_next(
+        &mut self,
+        behaviour: &mut SendFileBehaviour,
+        download: Download,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_id = behaviour.send_req(
+            download.peer_id,
+            FileRequest {
+                filename: download.filename.clone(),
+                offset: download.offset,
+                length: self.chunk_size,
+                hash: None,
+                compression: self.compression.clone(),
+                digests: false,
+                cancel: false,
+            },
+        )?;
+        self.in_flight.insert(request_id, download);
+        Ok(())
+    }
+
+    // Verifies the completed file and moves it into place through the store
+    async fn finish(
+        &mut self,
+        transfers: &mut Transfers,
+        store: &mut BlobStore,
+        mut download: Download,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Make sure the data is on disk before the rename makes it visible
+        if let Err(e) = sync(download.file.take()).await {
+            let reason = format!("could not write the file ({})", e);
+            abort(transfers, download, &reason).await;
+            return Ok(());
+        }
+
+        let hash = catalogue::to_hex(&download.hasher.clone().finalize());
+        if download.expected_hash.as_deref() != Some(hash.as_str()) {
+            abort(
+                transfers,
+                download,
+                "file is corrupted, hash does not match",
+            )
+            .await;
+            return Ok(());
+        }
+
+        // A file with the same name may have appeared while we were downloading
+        let Some(path) = target(self.collision, &download.path, &hash).await else {
+            skip(transfers, download).await;
+            return Ok(());
+        };
+
+        // The file is renamed into place, so an existing one is never left half written
+        let known = match store.save(&download.part_path, &hash, &path).await {
+            Ok(known) => known,
+            Err(e) => {
+                let reason = format!("could not save the file ({})", e);
+                abort(transfers, download, &reason).await;
+                return Ok(());
+            }
+        };
+        let _ = tokio::fs::remove_file(&download.state_path).await;
+        transfers.set_status(download.transfer_id, TransferStatus::Finished);
+        println!(
+            "Saved file to: {:?} ({} bytes, sha256 {})",
+            path, download.total_size, hash
+        );
+        if known {
+            println!("Same content as a file already received, stored once");
+        }
+        if let Some(compression) = transfers
+            .get(download.transfer_id)
+            .and_then(Transfer::compression)
+        {
+            println!("Compressed with {}", compression);
+        }
+        Ok(())
+    }
+
+    pub fn collision(&self) -> Collision {
+        self.collision
+    }
+
+    pub fn set_collision(&mut self, collision: Collision) {
+        self.collision = collision;
+    }
+
+    pub fn compression(&self) -> &[Compression] {
+        &self.compression
+    }
+
+    pub fn set_compression(&mut self, compression: &[Compression]) {
+        self.compression = compression.to_vec();
+    }
+}
+
+// Flushes a received file to disk before it is moved into place
+pub async fn sync(file: Option<File>) -> Result<(), std::io::Error> {
+    if let Some(mut file) = file {
+        file.flush().await?;
+        file.sync_all().await?;
+    }
+    Ok(())
+}
+
+// Decides where a download with the given hash is saved, None if it should be skipped
+pub async fn target(collision: Collision, path: &Path, hash: &str) -> Option<PathBuf> {
+    if !path.exists() {
+        return Some(path.to_path_buf());
+    }
+    match collision {
+        Collision::Overwrite => Some(path.to_path_buf()),
+        Collision::Skip => None,
+        Collision::SkipIdentical if catalogue::hash_file(path).await.is_ok_and(|h| h == hash) => {
+            None
+        }
+        Collision::Rename | Collision::SkipIdentical => Some(free_path(path)),
+    }
+}
+
+// Finds the first unused name of the form "report (1).pdf"
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+// Reports a download that was not needed because the file already exists
+async fn skip(transfers: &mut Transfers, download: Download) {
+    transfers.set_status(download.transfer_id, TransferStatus::Skipped);
+    println!(
+        "Skipped bytestream for {}, {:?} already exists",
+        download.filename, download.path
+    );
+    remove_partial(download).await;
+}
+
+// Reports a failed download and removes its partial file
+async fn abort(transfers: &mut Transfers, download: Download, reason: &str) {
+    transfers.set_status(
+        download.transfer_id,
+        TransferStatus::Failed(reason.to_string()),
+    );
+    println!(
+        "Bytestream for {} failed at {} of {} bytes: {}",
+        download.filename, download.offset, download.total_size, reason
+    );
+    remove_partial(download).await;
+}
+
+// Reports a cancelled download and removes its partial file
+async fn cancel(transfers: &mut Transfers, download: Download, by: &str) {
+    transfers.set_status(download.transfer_id, TransferStatus::Cancelled);
+    println!(
+        "Bytestream for {} cancelled at {} by {}",
+        download.filename,
+        download.progress(),
+        by
+    );
+    remove_partial(download).await;
+}
+
+async fn remove_partial(download: Download) {
+    drop(download.file);
+    let _ = tokio::fs::remove_file(&download.part_path).await;
+    let _ = tokio::fs::remove_file(&download.state_path).await;
+}
+
+// Files being served to peers, capped so a few large uploads cannot starve everyone else.
+// Uploads are tracked by FileRequest::upload_key.
+pub struct Uploads {
+    max_active: usize,
+    // When each peer last asked for a chunk of each file
+    active: HashMap<(PeerId, String), Instant>,
+    // Uploads cancelled here, refused until the peer starts the file over
+    cancelled: HashSet<(PeerId, String)>,
+}
+
+impl Uploads {
+    pub fn new(max_active: usize) -> Self {
+        Uploads {
+            max_active: max_active.max(1),
+            active: HashMap::new(),
+            cancelled: HashSet::new(),
+        }
+    }
+
+    // Decides whether a chunk may be served, taking a slot if the upload is new
+    fn admit(
+        &mut self,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+        key: &str,
+        offset: u64,
+    ) -> Result<(), FileError> {
+        let key = (peer_id, key.to_string());
+        if self.cancelled.remove(&key) && offset > 0 {
+            return Err(FileError::Cancelled);
+        }
+        self.expire(transfers);
+        if !self.active.contains_key(&key) && self.active.len() >= self.max_active {
+            return Err(FileError::Busy);
+        }
+        self.active.insert(key, Instant::now());
+        Ok(())
+    }
+
+    // Peers that stopped asking for chunks give up their slot. Their uploads are done, as a
+    // fetch only asks each peer for some of the chunks and never says when it has them all.
+    pub fn expire(&mut self, transfers: &mut Transfers) {
+        self.active.retain(|(peer_id, key), last| {
+            let idle = last.elapsed() >= UPLOAD_IDLE_TIMEOUT;
+            if let Some(id) = transfers.active_upload(*peer_id, key).filter(|_| idle) {
+                transfers.set_status(id, TransferStatus::Finished);
+            }
+            !idle
+        });
+    }
+
+    fn release(&mut self, peer_id: PeerId, key: &str) {
+        self.active.remove(&(peer_id, key.to_string()));
+    }
+
+    // Cancels an upload, returning false if there is no such upload
+    pub fn cancel(&mut self, transfers: &mut Transfers, transfer_id: u64) -> bool {
+        let Some(transfer) = transfers.get(transfer_id) else {
+            return false;
+        };
+        if transfer.direction != Direction::Upload || transfer.status != TransferStatus::Active {
+            return false;
+        }
+        let (peer_id, key) = (transfer.peer_id, transfer.key.clone());
+        println!("Cancelled upload of {} to {}", transfer.filename, peer_id);
+        self.release(peer_id, &key);
+        // The peer learns of it from the answer to its next request
+        self.cancelled.insert((peer_id, key));
+        transfers.set_status(transfer_id, TransferStatus::Cancelled);
+        true
+    }
+
+    // Frees the slot of an upload the peer cancelled
+    pub fn cancelled_by_peer(
+        &mut self,
+        transfers: &mut Transfers,
+        peer_id: PeerId,
+        request: &FileRequest,
+    ) {
+        self.release(peer_id, request.upload_key());
+        if let Some(id) = transfers.active_upload(peer_id, request.upload_key()) {
+            println!(
+                "Peer {} cancelled the upload of {}",
+                peer_id, request.filename
+            );
+            transfers.set_status(id, TransferStatus::Cancelled);
+        }
+    }
+
+    // Number of uploads running and the most that may run at once
+    pub fn slots(&mut self, transfers: &mut Transfers) -> (usize, usize) {
+        self.expire(transfers);
+        (self.active.len(), self.max_active)
+    }
+
+    pub fn set_max_active(&mut self, max_active: usize) {
+        self.max_active = max_active.max(1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferStatus {
+    Active,
+    // Waiting for a download slot, see Downloads
+    Queued,
+    Paused,
+    Finished,
+    // The file already existed, see Collision
+    Skipped,
+    Cancelled,
+    Failed(String),
+}
+
+impl TransferStatus {
+    // Heading the status is grouped under in /transfers
+    pub fn label(&self) -> &'static str {
+        match self {
+            TransferStatus::Active => "Active",
+            TransferStatus::Queued => "Queued",
+            TransferStatus::Paused => "Paused",
+            TransferStatus::Finished => "Finished",
+            TransferStatus::Skipped => "Skipped",
+            TransferStatus::Cancelled => "Cancelled",
+            TransferStatus::Failed(_) => "Failed",
+        }
+    }
+}
+
+// Progress of a single transfer in either direction
+pub struct Transfer {
+    pub id: u64,
+    pub direction: Direction,
+    pub peer_id: PeerId,
+    pub filename: String,
+    // What the upload is tracked by, see FileRequest::upload_key
+    key: String,
+    pub done: u64,
+    pub total: u64,
+    pub status: TransferStatus,
+    // Bytes sent or received on the wire, less than done when chunks were compressed
+    pub wire: u64,
+    pub encoding: Option<Compression>,
+    // Rate is measured from when the transfer last became active
+    started: Instant,
+    started_done: u64,
+    // Last 10% step printed, so progress is shown without flooding the chat
+    reported_step: u64,
+}
+
+impl Transfer {
+    // Average bytes per second since the transfer became active
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.done.saturating_sub(self.started_done) as f64 / elapsed
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if rate <= 0.0 || self.total <= self.done {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (self.total - self.done) as f64 / rate,
+        ))
+    }
+
+    // Describes what compression saved, if any chunk was compressed
+    pub fn compression(&self) -> Option<String> {
+        let encoding = self.encoding?;
+        Some(format!(
+            "{}, {} on the wire, {}% of {}",
+            encoding,
+            catalogue::format_size(self.wire),
+            self.wire * 100 / self.done.max(1),
+            catalogue::format_size(self.done)
+        ))
+    }
+
+    fn percent(&self) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        self.done * 100 / self.total
+    }
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (verb, preposition) = match self.direction {
+            Direction::Download => ("Download", "from"),
+            Direction::Upload => ("Upload", "to"),
+        };
+        write!(
+            f,
+            "#{} {} {} {} {}: {}% ({} of {})",
+            self.id,
+            verb,
+            self.filename,
+            preposition,
+            self.peer_id,
+            self.percent(),
+            catalogue::format_size(self.done),
+            catalogue::format_size(self.total)
+        )?;
+        if let Some(compression) = self.compression() {
+            write!(f, " [{}]", compression)?;
+        }
+        match &self.status {
+            TransferStatus::Active => {
+                write!(f, " {}/s", catalogue::format_size(self.rate() as u64))?;
+                if let Some(eta) = self.eta() {
+                    write!(f, " ETA {}", format_duration(eta))?;
                 }
+                Ok(())
             }
-            Err(open_error) => {
-                eprintln!("Failed to open file: {:?} - {}", path, open_error);
+            TransferStatus::Failed(reason) => write!(f, " - {}", reason),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Every transfer of this session, in both directions
+#[derive(Default)]
+pub struct Transfers {
+    next_id: u64,
+    transfers: Vec<Transfer>,
+}
+
+impl Transfers {
+    // Registers a new transfer and returns its id
+    pub fn add(
+        &mut self,
+        direction: Direction,
+        peer_id: PeerId,
+        filename: &str,
+        status: TransferStatus,
+    ) -> u64 {
+        self.next_id += 1;
+        self.transfers.push(Transfer {
+            id: self.next_id,
+            direction,
+            peer_id,
+            filename: filename.to_string(),
+            key: filename.to_string(),
+            done: 0,
+            total: 0,
+            status,
+            wire: 0,
+            encoding: None,
+            started: Instant::now(),
+            started_done: 0,
+            reported_step: 0,
+        });
+        self.next_id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Transfer> {
+        self.transfers.iter().find(|t| t.id == id)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Option<&mut Transfer> {
+        self.transfers.iter_mut().find(|t| t.id == id)
+    }
+
+    // Records bytes done, printing a progress line at every 10% step
+    pub fn progress(&mut self, id: u64, done: u64, total: u64) {
+        let Some(transfer) = self.get_mut(id) else {
+            return;
+        };
+        transfer.done = done;
+        transfer.total = total;
+
+        let step = transfer.percent() / 10;
+        if step > transfer.reported_step && done < total {
+            transfer.reported_step = step;
+            println!("{}", transfer);
+        }
+    }
+
+    pub fn set_status(&mut self, id: u64, status: TransferStatus) {
+        if let Some(transfer) = self.get_mut(id) {
+            // Restart the rate measurement whenever the transfer picks up again
+            if status == TransferStatus::Active && transfer.status != TransferStatus::Active {
+                transfer.started = Instant::now();
+                transfer.started_done = transfer.done;
             }
+            transfer.status = status;
         }
-        Ok(bytes)
     }
+
+    // The upload to a peer that is in progress under an upload key, if any
+    fn active_upload(&self, peer_id: PeerId, key: &str) -> Option<u64> {
+        self.transfers
+            .iter()
+            .find(|t| {
+                t.direction == Direction::Upload
+                    && t.peer_id == peer_id
+                    && t.key == key
+                    && t.status == TransferStatus::Active
+            })
+            .map(|t| t.id)
+    }
+
+    // Tracks an upload chunk, starting a new upload entry for the first chunk
+    pub fn upload_progress(
+        &mut self,
+        peer_id: PeerId,
+        request: &FileRequest,
+        chunk: &FileChunk,
+    ) -> u64 {
+        let key = request.upload_key();
+        let (id, done) = match self.active_upload(peer_id, key) {
+            Some(id) => (id, self.get(id).map_or(0, |t| t.done)),
+            None => {
+                let id = self.add(
+                    Direction::Upload,
+                    peer_id,
+                    &chunk.filename,
+                    TransferStatus::Active,
+                );
+                if let Some(transfer) = self.get_mut(id) {
+                    transfer.key = key.to_string();
+                }
+                // A download resumed by name already has the bytes before its offset, while a
+                // fetch spreads its chunks over several peers and only what we serve counts
+                let start = if request.hash.is_none() {
+                    chunk.offset
+                } else {
+                    0
+                };
+                (id, start)
+            }
+        };
+
+        // Counts what was served, as chunks may come in any order
+        let done = (done + chunk.data.len() as u64).min(chunk.total_size);
+        self.progress(id, done, chunk.total_size);
+        if done >= chunk.total_size {
+            self.set_status(id, TransferStatus::Finished);
+        }
+        id
+    }
+
+    // Records the bytes a chunk took on the wire, and its encoding if it was compressed
+    pub fn wire(&mut self, id: u64, bytes: u64, encoding: Option<Compression>) {
+        if let Some(transfer) = self.get_mut(id) {
+            transfer.wire += bytes;
+            if encoding.is_some() {
+                transfer.encoding = encoding;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transfer> {
+        self.transfers.iter()
+    }
+}
+
+// Formats a duration as e.g. 1h 02m, 3m 05s or 12s
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// Progress of a partial download, saved next to its .part file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PartialState {
+    peer_id: String,
+    filename: String,
+    // Missing in state saved by older versions, which always used download_path
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    priority: Priority,
+    offset: u64,
+    total_size: u64,
+    expected_hash: Option<String>,
+    // SHA-256 of the first offset bytes of the .part file
+    partial_hash: String,
 }
 
 // file exchange protocol for our app
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct FileRequest(pub String);
+pub struct FileRequest {
+    pub filename: String,
+    pub offset: u64,
+    pub length: u64,
+    // Names the file by content hash instead, filename is then ignored
+    #[serde(default)]
+    pub hash: Option<String>,
+    // Encodings the requester can decompress, the sender may use one if it makes the chunk smaller
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    // Asks for the SHA-256 of every length sized chunk instead of data, see Fetches
+    #[serde(default)]
+    pub digests: bool,
+    // Tells the sender we gave up on the file, so it can free the upload slot
+    #[serde(default)]
+    pub cancel: bool,
+}
+
+impl FileRequest {
+    // What the upload serving this request is tracked by. Requests by hash keep the hash,
+    // as the name it is served under may change between chunks.
+    pub fn upload_key(&self) -> &str {
+        self.hash.as_deref().unwrap_or(&self.filename)
+    }
+}
 
+// A range of a file sent in answer to a FileRequest
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct FileResponse {
+pub struct FileChunk {
     pub filename: String,
+    pub offset: u64,
+    pub total_size: u64,
+    // Hex encoded SHA-256 of the whole file, checked by the receiver once all chunks arrive
+    pub hash: String,
     pub data: Vec<u8>,
+    // Hex encoded SHA-256 of each chunk, only sent when the request asked for digests
+    #[serde(default)]
+    pub digests: Vec<String>,
+    // Set when data is compressed
+    #[serde(default)]
+    pub encoding: Option<Compression>,
+}
+
+impl FileChunk {
+    // Bytes of data and digests the chunk carries, what bandwidth limits are booked by
+    pub fn size(&self) -> u64 {
+        (self.data.len() + self.digests.iter().map(String::len).sum::<usize>()) as u64
+    }
+
+    // Compresses the data if the requester accepts it and the file is not compressed already
+    fn compress(mut self, accepted: &[Compression]) -> Self {
+        if compression::is_compressed(&self.filename) {
+            return self;
+        }
+        if let Some((encoding, data)) = compression::compress(&self.data, accepted) {
+            self.data = data;
+            self.encoding = Some(encoding);
+        }
+        self
+    }
+
+    // Restores the data of a compressed chunk, which may hold at most max_len bytes
+    pub fn decompress(mut self, max_len: u64) -> Result<Self, String> {
+        if let Some(encoding) = self.encoding.take() {
+            self.data = compression::decompress(&self.data, encoding, max_len as usize)
+                .map_err(|e| format!("invalid {} data: {}", encoding, e))?;
+        }
+        Ok(self)
+    }
+}
+
+// Why a peer could not send the requested file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileError {
+    NotFound,
+    PermissionDenied,
+    // The requested chunk is larger than MAX_CHUNK_SIZE
+    TooLarge,
+    Refused(String),
+    // The peer must approve the request first, see ServePolicy
+    AwaitingApproval,
+    // Every upload slot is taken, the requester should try again later
+    Busy,
+    Cancelled,
+    Unreadable(String),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::NotFound => write!(f, "file not found"),
+            FileError::PermissionDenied => write!(f, "permission denied"),
+            FileError::TooLarge => write!(f, "requested chunk is too large"),
+            FileError::Refused(reason) => write!(f, "request refused ({})", reason),
+            FileError::AwaitingApproval => write!(f, "waiting for the peer to approve"),
+            FileError::Busy => write!(f, "peer is busy, try again later"),
+            FileError::Cancelled => write!(f, "transfer was cancelled"),
+            FileError::Unreadable(reason) => write!(f, "file could not be read ({})", reason),
+        }
+    }
+}
+
+impl From<std::io::Error> for FileError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => FileError::NotFound,
+            std::io::ErrorKind::PermissionDenied => FileError::PermissionDenied,
+            _ => FileError::Unreadable(e.to_string()),
+        }
+    }
+}
+
+impl From<ShareError> for FileError {
+    fn from(e: ShareError) -> Self {
+        match e {
+            ShareError::Refused(reason) => FileError::Refused(reason),
+            ShareError::Io(e) => e.into(),
+        }
+    }
 }
+
+pub type FileResponse = Result<FileChunk, FileError>;
@@ -0,0 +1,413 @@
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+// Why a requested path could not be served
+#[derive(Debug)]
+pub enum ShareError {
+    // The path points outside the share and must never be read
+    Refused(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareError::Refused(reason) => write!(f, "refused: {}", reason),
+            ShareError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ShareError {}
+
+impl From<std::io::Error> for ShareError {
+    fn from(e: std::io::Error) -> Self {
+        ShareError::Io(e)
+    }
+}
+
+// A directory whose contents may be served to peers, and nothing outside it.
+// Extra roots appear inside it under their alias, as if they were subdirectories.
+pub struct ShareRoot {
+    root: PathBuf,
+    aliases: BTreeMap<String, PathBuf>,
+}
+
+impl ShareRoot {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ShareRoot::with_aliases(root, BTreeMap::new())
+    }
+
+    pub fn with_aliases(root: impl Into<PathBuf>, aliases: BTreeMap<String, PathBuf>) -> Self {
+        ShareRoot {
+            root: root.into(),
+            aliases,
+        }
+    }
+
+    // Every directory the share is made of, the main root first
+    pub fn roots(&self) -> Vec<&Path> {
+        let mut roots = vec![self.root.as_path()];
+        roots.extend(self.aliases.values().map(PathBuf::as_path));
+        roots
+    }
+
+    // Splits a checked relative path into the root it lives under and the path inside that root
+    fn locate<'a>(&'a self, relative: &'a Path) -> (&'a Path, &'a Path) {
+        let mut components = relative.components();
+        if let Some(Component::Normal(first)) = components.next() {
+            if let Some(root) = first.to_str().and_then(|alias| self.aliases.get(alias)) {
+                return (root, components.as_path());
+            }
+        }
+        (&self.root, relative)
+    }
+
+    // Maps a path requested by a peer to a file inside the share.
+    // The returned path is canonical, so symlinks have already been followed and checked.
+    pub fn resolve(&self, peer_id: &PeerId, requested: &str) -> Result<PathBuf, ShareError> {
+        let result = self.check(requested);
+        if let Err(ShareError::Refused(reason)) = &result {
+            eprintln!(
+                "Refused request from {} for {:?}: {}",
+                peer_id, requested, reason
+            );
+        }
+        result
+    }
+
+    // The name files() lists a requested file under, however the peer spelled it.
+    // Symlinks are followed, so a link and the file it points at have the same name.
+    pub fn canonical_name(&self, requested: &str) -> Option<String> {
+        let path = self.check(requested).ok()?;
+        // Aliases first, as their names hide whatever the main root has under them
+        for (alias, root) in &self.aliases {
+            if let Some(relative) = root
+                .canonicalize()
+                .ok()
+                .and_then(|root| path.strip_prefix(root).ok().map(to_name))
+            {
+                return Some(format!("{}/{}", alias, relative));
+            }
+        }
+        let root = self.root.canonicalize().ok()?;
+        path.strip_prefix(root).ok().map(to_name)
+    }
+
+    fn check(&self, requested: &str) -> Result<PathBuf, ShareError> {
+        let relative = Path::new(requested);
+        if requested.is_empty() || requested.contains('\0') {
+            return Err(ShareError::Refused("invalid path".to_string()));
+        }
+
+        // Only plain names are allowed, so "..", "/", "C:" and "." are all rejected up front
+        for component in relative.components() {
+            match component {
+                Component::Normal(_) => {}
+                Component::ParentDir => {
+                    return Err(ShareError::Refused("parent directory".to_string()))
+                }
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(ShareError::Refused("absolute path".to_string()))
+                }
+                Component::CurDir => {
+                    return Err(ShareError::Refused("relative component".to_string()))
+                }
+            }
+        }
+
+        // Canonicalizing follows symlinks, anything that lands outside the root is an escape
+        let (root, relative) = self.locate(relative);
+        let root = root.canonicalize()?;
+        let path = root.join(relative).canonicalize()?;
+        if !path.starts_with(&root) {
+            return Err(ShareError::Refused("outside the share".to_string()));
+        }
+        Ok(path)
+    }
+
+    // Lists every file inside the share as (name relative to the share, canonical path).
+    // Symlinks that lead outside their root are skipped, and so are directories that
+    // cannot be read, so one missing share does not hide all the others.
+    pub fn files(&self) -> Vec<(String, PathBuf)> {
+        // An alias hides whatever the main root has under the same name
+        let mut files = walk(&self.root)
+            .into_iter()
+            .filter(|(name, _)| {
+                let first = name.split('/').next().unwrap_or_default();
+                !self.aliases.contains_key(first)
+            })
+            .collect::<Vec<_>>();
+        for (alias, root) in &self.aliases {
+            for (name, path) in walk(root) {
+                files.push((format!("{}/{}", alias, name), path));
+            }
+        }
+        files.sort();
+        files
+    }
+}
+
+// Lists every file below a directory as (name relative to it, canonical path)
+fn walk(root: &Path) -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(e) => {
+            eprintln!("Skipping shared directory {:?}: {}", root, e);
+            return files;
+        }
+    };
+    let mut dirs = vec![PathBuf::new()];
+    // Guards against symlinked directories that loop back on themselves
+    let mut visited = HashSet::from([root.clone()]);
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(root.join(&dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Skipping shared directory {:?}: {}", root.join(&dir), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let relative = dir.join(entry.file_name());
+            let Ok(path) = root.join(&relative).canonicalize() else {
+                continue;
+            };
+            if !path.starts_with(&root) {
+                continue;
+            }
+
+            if path.is_dir() {
+                if visited.insert(path) {
+                    dirs.push(relative);
+                }
+            } else if path.is_file() {
+                files.push((to_name(&relative), path));
+            }
+        }
+    }
+    files
+}
+
+// Joins the components of a relative path with '/', the form peers see names in
+fn to_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A share with a subdirectory, an aliased extra root and a directory outside both,
+    // removed again when the test ends
+    struct Fixture {
+        dir: PathBuf,
+        share: ShareRoot,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("swapbytes-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for sub in ["share/sub", "extra", "outside"] {
+                fs::create_dir_all(dir.join(sub)).unwrap();
+            }
+            fs::write(dir.join("share/a.txt"), "a").unwrap();
+            fs::write(dir.join("share/sub/b.txt"), "b").unwrap();
+            fs::write(dir.join("extra/c.txt"), "c").unwrap();
+            fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+
+            let aliases = BTreeMap::from([("extra".to_string(), dir.join("extra"))]);
+            let share = ShareRoot::with_aliases(dir.join("share"), aliases);
+            Fixture { dir, share }
+        }
+
+        fn path(&self, relative: &str) -> PathBuf {
+            self.dir.join(relative).canonicalize().unwrap()
+        }
+
+        fn refused(&self, requested: &str) -> bool {
+            matches!(self.share.check(requested), Err(ShareError::Refused(_)))
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn resolves_plain_names() {
+        let fixture = Fixture::new("plain");
+        assert_eq!(
+            fixture.share.check("a.txt").unwrap(),
+            fixture.path("share/a.txt")
+        );
+        assert_eq!(
+            fixture.share.check("sub/b.txt").unwrap(),
+            fixture.path("share/sub/b.txt")
+        );
+    }
+
+    #[test]
+    fn refuses_parent_directories() {
+        let fixture = Fixture::new("parent");
+        assert!(fixture.refused(".."));
+        assert!(fixture.refused("../outside/secret.txt"));
+        assert!(fixture.refused("sub/../../outside/secret.txt"));
+        assert!(fixture.refused("sub/../a.txt"));
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        let fixture = Fixture::new("absolute");
+        assert!(fixture.refused("/etc/passwd"));
+        let inside = fixture.path("share/a.txt");
+        assert!(fixture.refused(&inside.to_string_lossy()));
+    }
+
+    #[test]
+    fn refuses_leading_current_directory() {
+        let fixture = Fixture::new("current");
+        assert!(fixture.refused("."));
+        assert!(fixture.refused("./a.txt"));
+    }
+
+    #[test]
+    fn normalises_repeated_and_inner_separators() {
+        let fixture = Fixture::new("separators");
+        let expected = fixture.path("share/sub/b.txt");
+        assert_eq!(fixture.share.check("sub//b.txt").unwrap(), expected);
+        assert_eq!(fixture.share.check("sub/./b.txt").unwrap(), expected);
+    }
+
+    #[test]
+    fn refuses_empty_and_nul() {
+        let fixture = Fixture::new("nul");
+        assert!(fixture.refused(""));
+        assert!(fixture.refused("a.txt\0"));
+        assert!(fixture.refused("sub\0/b.txt"));
+    }
+
+    #[test]
+    fn missing_files_are_not_refusals() {
+        let fixture = Fixture::new("missing");
+        assert!(matches!(
+            fixture.share.check("nothing.txt"),
+            Err(ShareError::Io(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_share() {
+        let fixture = Fixture::new("symlinks");
+        let share = fixture.dir.join("share");
+        std::os::unix::fs::symlink(fixture.dir.join("outside/secret.txt"), share.join("file"))
+            .unwrap();
+        std::os::unix::fs::symlink(fixture.dir.join("outside"), share.join("dir")).unwrap();
+        std::os::unix::fs::symlink("../../outside", share.join("sub/up")).unwrap();
+
+        assert!(fixture.refused("file"));
+        assert!(fixture.refused("dir/secret.txt"));
+        assert!(fixture.refused("sub/up/secret.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_inside_the_share() {
+        let fixture = Fixture::new("inner-symlinks");
+        let share = fixture.dir.join("share");
+        std::os::unix::fs::symlink(share.join("sub/b.txt"), share.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink("sub", share.join("dir")).unwrap();
+
+        let expected = fixture.path("share/sub/b.txt");
+        assert_eq!(fixture.share.check("link.txt").unwrap(), expected);
+        assert_eq!(fixture.share.check("dir/b.txt").unwrap(), expected);
+    }
+
+    #[test]
+    fn resolves_aliases_to_their_root() {
+        let fixture = Fixture::new("alias");
+        assert_eq!(
+            fixture.share.check("extra/c.txt").unwrap(),
+            fixture.path("extra/c.txt")
+        );
+        assert!(fixture.refused("extra/../outside/secret.txt"));
+        assert!(fixture.refused("extra/.."));
+    }
+
+    #[test]
+    fn canonical_names_match_the_listing() {
+        let fixture = Fixture::new("names");
+        for requested in ["sub/b.txt", "sub//b.txt", "sub/./b.txt"] {
+            assert_eq!(
+                fixture.share.canonical_name(requested).as_deref(),
+                Some("sub/b.txt")
+            );
+        }
+        assert_eq!(
+            fixture.share.canonical_name("extra/c.txt").as_deref(),
+            Some("extra/c.txt")
+        );
+        assert_eq!(fixture.share.canonical_name("../outside/secret.txt"), None);
+
+        let names = fixture
+            .share
+            .files()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.txt", "extra/c.txt", "sub/b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonical_names_follow_symlinks() {
+        let fixture = Fixture::new("symlink-names");
+        let share = fixture.dir.join("share");
+        std::os::unix::fs::symlink("sub/b.txt", share.join("link.txt")).unwrap();
+        assert_eq!(
+            fixture.share.canonical_name("link.txt").as_deref(),
+            Some("sub/b.txt")
+        );
+    }
+
+    #[test]
+    fn missing_aliases_are_skipped() {
+        let fixture = Fixture::new("missing-alias");
+        fs::remove_dir_all(fixture.dir.join("extra")).unwrap();
+        let names = fixture
+            .share
+            .files()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.txt", "sub/b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_an_alias() {
+        let fixture = Fixture::new("alias-symlinks");
+        let extra = fixture.dir.join("extra");
+        std::os::unix::fs::symlink(fixture.dir.join("outside/secret.txt"), extra.join("file"))
+            .unwrap();
+        std::os::unix::fs::symlink(fixture.dir.join("share"), extra.join("share")).unwrap();
+
+        assert!(fixture.refused("extra/file"));
+        // Another root of the same share is still outside this alias
+        assert!(fixture.refused("extra/share/a.txt"));
+    }
+}
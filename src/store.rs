@@ -0,0 +1,123 @@
+use crate::catalogue::{self, Catalogue};
+use crate::search;
+use crate::share::ShareRoot;
+
+use std::path::{Path, PathBuf};
+
+// Downloaded files kept by content hash, as <hash>/<name>. Saved downloads are hard links to
+// their blob, so identical content is stored once and can be served to peers asking by hash.
+pub struct BlobStore {
+    root: PathBuf,
+    // Serving goes through a share over the store, with the same checks as the ByteBeam
+    share: ShareRoot,
+    // Hashes added since take_saved was last called, so they can be announced
+    saved: Vec<String>,
+}
+
+impl BlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        if let Err(e) = std::fs::create_dir_all(&root) {
+            eprintln!("Could not create the store at {:?}: {}", root, e);
+        }
+        BlobStore {
+            share: ShareRoot::new(&root),
+            root,
+            saved: Vec::new(),
+        }
+    }
+
+    pub fn share(&self) -> &ShareRoot {
+        &self.share
+    }
+
+    fn blob_dir(&self, hash: &str) -> Option<PathBuf> {
+        search::is_hash(hash).then(|| self.root.join(hash.to_lowercase()))
+    }
+
+    fn blob(&self, hash: &str) -> Option<PathBuf> {
+        std::fs::read_dir(self.blob_dir(hash)?)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|path| path.is_file())
+    }
+
+    // Finds the stored file with a hash, as a name inside the store's share.
+    // Saved downloads share their blob, so one edited in place is dropped instead of served.
+    pub async fn find(&self, catalogue: &mut Catalogue, hash: &str) -> Option<String> {
+        let blob = self.blob(hash)?;
+        let (_, _, actual) = catalogue.hash(&blob).await.ok()?;
+        if !actual.eq_ignore_ascii_case(hash) {
+            eprintln!(
+                "Stored copy {:?} was modified, it will no longer be served",
+                blob
+            );
+            if let Some(dir) = self.blob_dir(hash) {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            return None;
+        }
+        let name = blob.file_name()?.to_string_lossy().into_owned();
+        Some(format!("{}/{}", hash.to_lowercase(), name))
+    }
+
+    // Saves a verified download at path, moving its content into the store unless it is there
+    // already. Returns true if the content was already stored.
+    pub async fn save(
+        &mut self,
+        part_path: &Path,
+        hash: &str,
+        path: &Path,
+    ) -> Result<bool, std::io::Error> {
+        let (Some(dir), Some(name)) = (self.blob_dir(hash), path.file_name()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot store {:?} under hash {}", path, hash),
+            ));
+        };
+
+        // A blob changed through one of its links no longer holds this content
+        let (blob, known) = match self.blob(hash) {
+            Some(blob) if catalogue::hash_file(&blob).await.is_ok_and(|h| h == hash) => {
+                tokio::fs::remove_file(part_path).await?;
+                (blob, true)
+            }
+            _ => {
+                let _ = tokio::fs::remove_dir_all(&dir).await;
+                tokio::fs::create_dir_all(&dir).await?;
+                let blob = dir.join(name);
+                tokio::fs::rename(part_path, &blob).await?;
+                self.saved.push(hash.to_string());
+                (blob, false)
+            }
+        };
+        self.link(&blob, part_path, path).await?;
+        Ok(known)
+    }
+
+    // Puts the blob at path by way of part_path, so an existing file is replaced in one step
+    async fn link(&self, blob: &Path, part_path: &Path, path: &Path) -> Result<(), std::io::Error> {
+        // Not every filesystem supports hard links, a copy still works, just without the saving
+        if tokio::fs::hard_link(blob, part_path).await.is_err() {
+            tokio::fs::copy(blob, part_path).await?;
+        }
+        tokio::fs::rename(part_path, path).await
+    }
+
+    // Hashes of every stored file
+    pub fn hashes(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.root) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| search::is_hash(name))
+            .collect()
+    }
+
+    pub fn take_saved(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.saved)
+    }
+}
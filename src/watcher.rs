@@ -0,0 +1,146 @@
+use crate::catalogue::SharedFile;
+
+use libp2p::gossipsub;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant, Interval};
+
+// Internal topic on which peers announce the files they start and stop sharing
+pub const SHARES_TOPIC: &str = "swapbytes/shares";
+
+// Changes are collected until the share has been quiet this long, so a file being copied in
+// is announced once it is complete
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+// How often the share is rescanned when the platform cannot watch it
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Files a peer started or stopped sharing, published on SHARES_TOPIC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareUpdate {
+    pub sender: String,
+    // New files, and files whose contents changed
+    pub added: Vec<SharedFile>,
+    pub removed: Vec<SharedFile>,
+}
+
+impl ShareUpdate {
+    pub fn topic() -> gossipsub::IdentTopic {
+        gossipsub::IdentTopic::new(SHARES_TOPIC)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+}
+
+// Watches the ByteBeam and any extra share roots for changes and keeps an index of the files in it with their hashes
+pub struct ShareWatcher {
+    // Events stop when the watcher is dropped, None if watching failed and the share is polled
+    watcher: Option<RecommendedWatcher>,
+    events: mpsc::UnboundedReceiver<()>,
+    poll: Interval,
+    rescan_at: Option<Instant>,
+    index: BTreeMap<String, SharedFile>,
+}
+
+impl ShareWatcher {
+    // Starts watching the roots of a share and everything below them, creating the first,
+    // the ByteBeam itself, if needed
+    pub fn new(roots: &[&Path]) -> Self {
+        let (sender, events) = mpsc::unbounded_channel();
+        let watcher = roots
+            .first()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .map_err(notify::Error::io)
+            .and_then(|_| {
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    // Reading a file, as serving it does, is not a change
+                    if event.is_ok_and(|e| !e.kind.is_access()) {
+                        let _ = sender.send(());
+                    }
+                })
+            })
+            .and_then(|mut watcher| {
+                for root in roots {
+                    watcher.watch(root, RecursiveMode::Recursive)?;
+                }
+                Ok(watcher)
+            });
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!(
+                    "Could not watch {:?} for changes, checking every {}s instead: {}",
+                    roots,
+                    POLL_INTERVAL.as_secs(),
+                    e
+                );
+                None
+            }
+        };
+
+        ShareWatcher {
+            watcher,
+            events,
+            poll: time::interval_at(Instant::now() + POLL_INTERVAL, POLL_INTERVAL),
+            rescan_at: None,
+            index: BTreeMap::new(),
+        }
+    }
+
+    // Waits until something in the share may have changed
+    pub async fn changed(&mut self) {
+        if self.watcher.is_some() {
+            self.events.recv().await;
+        } else {
+            self.poll.tick().await;
+        }
+    }
+
+    // Puts off the rescan until the share has settled
+    pub fn touch(&mut self) {
+        self.rescan_at = Some(Instant::now() + SETTLE_TIME);
+    }
+
+    pub fn rescan_at(&self) -> Option<Instant> {
+        self.rescan_at
+    }
+
+    // Takes the pending rescan before it runs, so changes made during the scan get one of their own
+    pub fn start_rescan(&mut self) {
+        self.rescan_at = None;
+    }
+
+    // Replaces the index with a fresh scan, returning what was added and removed since the last one
+    pub fn update(&mut self, files: Vec<SharedFile>) -> (Vec<SharedFile>, Vec<SharedFile>) {
+        let index = files
+            .into_iter()
+            .map(|file| (file.name.clone(), file))
+            .collect::<BTreeMap<String, SharedFile>>();
+        // Touching a file without changing its contents is not worth announcing
+        let added = index
+            .values()
+            .filter(|file| self.index.get(&file.name).map(|f| &f.hash) != Some(&file.hash))
+            .cloned()
+            .collect();
+        let removed = self
+            .index
+            .values()
+            .filter(|file| !index.contains_key(&file.name))
+            .cloned()
+            .collect();
+        self.index = index;
+        (added, removed)
+    }
+}